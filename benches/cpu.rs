@@ -0,0 +1,78 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use nes_core::cpu::{CPU, MEM};
+use nes_core::snake;
+
+// LDY #0; loop_y: LDX #0; loop_x: INX; BNE loop_x; INY; BNE loop_y; BRK
+const TIGHT_LOOP: [u8; 11] = [0xa0, 0x00, 0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0xc8, 0xd0, 0xf8, 0x00];
+const TIGHT_LOOP_INSTRUCTIONS: u64 = 1 + 256 * (1 + 256 * 2 + 2);
+
+// Without input the snake hits the wall after ~15k instructions, stay below that
+const SNAKE_INSTRUCTIONS: u64 = 10_000;
+
+fn tight_loop(c: &mut Criterion) {
+    let mut cpu = CPU::new();
+    cpu.load(TIGHT_LOOP.to_vec()).unwrap();
+
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(TIGHT_LOOP_INSTRUCTIONS));
+    group.bench_function("tight_loop", |b| {
+        b.iter(|| {
+            cpu.reset();
+            cpu.run().unwrap();
+            black_box(cpu.register_y)
+        })
+    });
+    group.finish();
+}
+
+fn snake(c: &mut Criterion) {
+    let mut cpu = CPU::new();
+    cpu.load(snake::GAME_CODE.to_vec()).unwrap();
+
+    let mut group = c.benchmark_group("snake");
+    group.throughput(Throughput::Elements(SNAKE_INSTRUCTIONS));
+    group.bench_function("fixed_instructions", |b| {
+        b.iter(|| {
+            cpu.reset();
+            let mut executed = 0;
+            cpu.run_until(|_| {
+                executed += 1;
+                executed > SNAKE_INSTRUCTIONS
+            })
+            .unwrap();
+            black_box(cpu.program_counter)
+        })
+    });
+    group.finish();
+}
+
+fn memory(c: &mut Criterion) {
+    let mut cpu = CPU::new();
+
+    let mut group = c.benchmark_group("memory");
+    group.throughput(Throughput::Elements(0x0800));
+    group.bench_function("ram_write_read", |b| {
+        b.iter(|| {
+            let mut sum: u8 = 0;
+            for addr in 0..0x0800u16 {
+                cpu.mem_write(black_box(addr), addr as u8);
+                sum = sum.wrapping_add(cpu.mem_read(black_box(addr)));
+            }
+            black_box(sum)
+        })
+    });
+    group.bench_function("read_u16", |b| {
+        b.iter(|| {
+            let mut sum: u16 = 0;
+            for addr in 0..0x0800u16 {
+                sum = sum.wrapping_add(cpu.mem_read_u16(black_box(addr)));
+            }
+            black_box(sum)
+        })
+    });
+    group.finish();
+}
+
+// Full-frame emulation joins this suite once the PPU drives frame timing
+criterion_group!(benches, tight_loop, snake, memory);
+criterion_main!(benches);
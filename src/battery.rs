@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+
+// Battery backed WRAM of `game.nes` persists in `game.sav`
+pub fn save_path_for_rom<P: AsRef<Path>>(rom: P) -> PathBuf {
+    rom.as_ref().with_extension("sav")
+}
+
+pub struct BatterySave {
+    path: PathBuf,
+    flushed: Vec<u8>,
+}
+
+impl BatterySave {
+    // Cartridges without the battery bit get None, so their RAM never reaches the disk
+    pub fn new<P: AsRef<Path>>(rom_path: P, rom: &Rom) -> Option<BatterySave> {
+        if !rom.battery {
+            return None;
+        }
+        Some(BatterySave { path: save_path_for_rom(rom_path), flushed: Vec::new() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Returns false when there is no save file yet
+    pub fn load(&mut self, cpu: &mut CPU) -> io::Result<bool> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                cpu.load_save_ram(&data);
+                self.flushed = cpu.save_ram().to_vec();
+                Ok(true)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Writes the RAM if it changed since the last flush, returns whether it did.
+    // The file is replaced through a rename so a crash mid-write keeps the old save.
+    pub fn flush(&mut self, cpu: &CPU) -> io::Result<bool> {
+        let ram = cpu.save_ram();
+        if ram == &self.flushed[..] {
+            return Ok(false);
+        }
+
+        let tmp = self.path.with_extension("sav.tmp");
+        fs::write(&tmp, ram)?;
+        fs::rename(&tmp, &self.path)?;
+        self.flushed = ram.to_vec();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_ines;
+    use crate::cpu::MEM;
+
+    #[test]
+    fn test_battery_save_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nes_battery_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+
+        let no_battery = Rom::new(&test_ines(1, 0)).unwrap();
+        assert!(BatterySave::new(&rom_path, &no_battery).is_none());
+
+        let rom = Rom::new(&test_ines(1, 0b10)).unwrap();
+        let mut save = BatterySave::new(&rom_path, &rom).unwrap();
+        assert_eq!(save.path(), dir.join("game.sav"));
+
+        let mut cpu = CPU::new();
+        assert!(!save.load(&mut cpu).unwrap());
+        cpu.mem_write(0x6010, 0x42);
+        assert!(save.flush(&cpu).unwrap());
+        assert!(!save.flush(&cpu).unwrap());
+
+        let mut restored = CPU::new();
+        let mut save = BatterySave::new(&rom_path, &rom).unwrap();
+        assert!(save.load(&mut restored).unwrap());
+        assert_eq!(restored.mem_read(0x6010), 0x42);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+// Runs the emulator without a window, for screenshots and scripted checks
+use std::env;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+
+use nes_core::cartridge::Rom;
+use nes_core::cdl::CodeDataLogger;
+use nes_core::cpu::{CpuState, CPU};
+use nes_core::disasm;
+use nes_core::font;
+use nes_core::ntsc::{NtscFilter, NtscFilterParams, NtscPreset};
+use nes_core::palette::Palette;
+use nes_core::ppuview;
+use nes_core::profiler::Profiler;
+use nes_core::recorder::{Recorder, VideoFormat};
+use nes_core::region::{FrameClock, Region};
+use nes_core::screenshot;
+use nes_core::script::{ScriptHost, ScriptText};
+use nes_core::snake;
+use nes_core::symbols::SymbolTable;
+
+const CAPTURE_SAMPLE_RATE: u32 = 44100;
+const SCRIPT_TEXT_COLOR: (u8, u8, u8) = (255, 255, 255);
+
+const USAGE: &str = "\
+usage: nes_headless [options]
+  --rom <game.nes>                    run a ROM instead of the snake demo
+  --frames <count>                    frames to run, 60 by default
+  --screenshot <file.png>             save the last frame
+  --capture <base>                    record every frame to <base>.y4m and <base>.wav
+  --capture-format <y4m|rgb>          rgb writes lossless <base>.rgb with a <base>.txt sidecar
+  --palette <file.pal>                load a 192 or 1536 byte palette
+  --filter <composite|svideo|rgb>     NTSC video filter
+  --region <ntsc|pal|dendy>           console timing, detected from the ROM header by default
+  --script <file.rhai>                run a script, exits with an error if the script fails
+  --cdl <file.cdl>                    log code and data use of the ROM, adding to an existing log
+  --profile <base>                    write a profile to <base>.txt and flamegraph stacks to <base>.folded
+  --symbols <file>                    load labels from a .dbg, .nl, .mlb or addr=name file, may be repeated
+  --trace <file.log>                  write every executed instruction with the registers
+  --chr <file.png>                    save the pattern tables of every CHR ROM bank, stacked
+  --chr-palette <0-3>                 colours for --chr: grey, red, green or blue";
+
+struct Options {
+    rom: Option<String>,
+    frames: usize,
+    screenshot: Option<String>,
+    capture: Option<String>,
+    capture_format: VideoFormat,
+    palette: Option<String>,
+    filter: Option<NtscPreset>,
+    region: Option<Region>,
+    script: Option<String>,
+    cdl: Option<String>,
+    profile: Option<String>,
+    symbols: Vec<String>,
+    trace: Option<String>,
+    chr: Option<String>,
+    chr_palette: usize,
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        rom: None,
+        frames: 60,
+        screenshot: None,
+        capture: None,
+        capture_format: VideoFormat::Y4m,
+        palette: None,
+        filter: None,
+        region: None,
+        script: None,
+        cdl: None,
+        profile: None,
+        symbols: Vec::new(),
+        trace: None,
+        chr: None,
+        chr_palette: 0,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--rom" => options.rom = Some(value),
+            "--frames" => options.frames = value.parse().unwrap_or_else(|_| usage()),
+            "--screenshot" => options.screenshot = Some(value),
+            "--capture" => options.capture = Some(value),
+            "--capture-format" => options.capture_format = match value.as_str() {
+                "y4m" => VideoFormat::Y4m,
+                "rgb" => VideoFormat::RawRgb,
+                _ => usage(),
+            },
+            "--palette" => options.palette = Some(value),
+            "--filter" => options.filter = Some(NtscPreset::from_name(&value).unwrap_or_else(|| usage())),
+            "--region" => options.region = Some(Region::from_name(&value).unwrap_or_else(|| usage())),
+            "--script" => options.script = Some(value),
+            "--cdl" => options.cdl = Some(value),
+            "--profile" => options.profile = Some(value),
+            "--symbols" => options.symbols.push(value),
+            "--trace" => options.trace = Some(value),
+            "--chr" => options.chr = Some(value),
+            "--chr-palette" => options.chr_palette = match value.parse() {
+                Ok(index) if index < ppuview::VIEW_PALETTES.len() => index,
+                _ => usage(),
+            },
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let options = parse_options();
+
+    let mut cpu = CPU::new();
+    let rom = match &options.rom {
+        Some(path) => {
+            let raw = fs::read(path).unwrap_or_else(|err| fail(format!("Cannot read ROM {}: {}", path, err)));
+            let rom = Rom::new(&raw).unwrap_or_else(|err| fail(format!("Cannot load ROM {}: {}", path, err)));
+            cpu.load_rom(&rom).unwrap_or_else(|err| fail(format!("Cannot load ROM {}: {}", path, err)));
+            Some(rom)
+        }
+        None => {
+            cpu.load(snake::GAME_CODE.to_vec()).unwrap();
+            None
+        }
+    };
+    let region = options.region.or(rom.as_ref().and_then(|rom| rom.region)).unwrap_or_default();
+    cpu.reset();
+
+    let cdl = options.cdl.as_ref().map(|path| {
+        let rom = rom.as_ref().unwrap_or_else(|| fail("--cdl needs a ROM given with --rom".to_string()));
+        let logger = CodeDataLogger::load(path, rom).unwrap_or_else(|err| fail(format!("Cannot load CDL {}: {}", path, err)));
+        let logger = Rc::new(RefCell::new(logger));
+        CodeDataLogger::attach(&logger, &mut cpu);
+        logger
+    });
+
+    if options.chr.is_some() && rom.as_ref().is_none_or(|rom| rom.chr_rom.is_empty()) {
+        fail("--chr needs a ROM with CHR ROM given with --rom".to_string());
+    }
+
+    let palette = match &options.palette {
+        Some(path) => Palette::load(path).unwrap_or_else(|err| fail(format!("Cannot load palette {}: {}", path, err))),
+        None => Palette::default(),
+    };
+    let mut filter = options.filter.map(|preset| NtscFilter::new(NtscFilterParams::preset(preset), &palette));
+    let width = match filter {
+        Some(_) => NtscFilter::output_width(snake::SCREEN_WIDTH),
+        None => snake::SCREEN_WIDTH,
+    };
+    let mut render = |cpu: &CPU, texts: &[ScriptText]| {
+        let pixels = snake::screen_pixels(cpu);
+        let mut rgb = match &mut filter {
+            Some(filter) => filter.apply(&pixels, snake::SCREEN_WIDTH),
+            None => palette.render(&pixels),
+        };
+        for text in texts {
+            font::draw_text(&mut rgb, width, text.x, text.y, &text.text, SCRIPT_TEXT_COLOR);
+        }
+        rgb
+    };
+    let mut script = options.script.as_ref().map(|path| {
+        ScriptHost::load(path, &mut cpu).unwrap_or_else(|err| fail(format!("Cannot load script {}: {}", path, err)))
+    });
+    let mut texts = Vec::new();
+
+    let mut capture = options.capture.as_ref().map(|base| {
+        Recorder::start(
+            base, options.capture_format, width, snake::SCREEN_HEIGHT,
+            region.frame_rate_fraction(), CAPTURE_SAMPLE_RATE,
+        )
+        .unwrap_or_else(|err| fail(format!("Cannot start capture {}: {}", base, err)))
+    });
+
+    // A fixed xorshift keeps snake runs identical from one invocation to the next
+    let mut seed: u32 = 0x2545_F491;
+    let mut random = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed % 15) as u8 + 1
+    };
+
+    let mut profiler = options.profile.as_ref().map(|_| Profiler::new());
+    let mut symbols = SymbolTable::new(rom.as_ref().map_or(0, |rom| rom.prg_rom.len()));
+    for path in &options.symbols {
+        symbols.load(path).unwrap_or_else(|err| fail(format!("Cannot load symbols {}", err)));
+    }
+    let mut trace = options.trace.as_ref().map(|path| {
+        let file = fs::File::create(path).unwrap_or_else(|err| fail(format!("Cannot create trace {}: {}", path, err)));
+        BufWriter::new(file)
+    });
+    let mut trace_error = None;
+    // Why the run stopped early, reported once the trace, CDL and capture are written
+    let mut failure = None;
+    let mut clock = FrameClock::new(region);
+
+    for frame in 0..options.frames {
+        if let Some(host) = &mut script {
+            host.frame(&mut cpu);
+        }
+        let cycles = match options.rom {
+            Some(_) => clock.next_frame(),
+            None => snake::CYCLES_PER_FRAME,
+        };
+        let mut hooks = script.as_mut().filter(|host| host.wants_instructions());
+        let before = cpu.cycles;
+        let state = cpu.run_for_cycles_with_callback(cycles, |cpu| {
+            if options.rom.is_none() {
+                snake::write_random(cpu, random());
+            }
+            if let Some(host) = &mut hooks {
+                host.before_instruction(cpu);
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.before_instruction(cpu);
+            }
+            if let (Some(out), None) = (&mut trace, &trace_error) {
+                trace_error = writeln!(out, "{}", disasm::trace_line(cpu, &symbols)).err();
+            }
+        });
+        clock.frame_done(before, cpu.cycles);
+        if let Some(err) = trace_error.take() {
+            fail(format!("Cannot write trace: {}", err));
+        }
+        if let Some(profiler) = &mut profiler {
+            profiler.end_frame(&cpu);
+        }
+        if let Some(host) = &mut script {
+            if let Some(err) = host.take_error() {
+                failure = Some(format!("Script failed after {} frames: {}", frame, err));
+                break;
+            }
+            texts = host.take_texts();
+        }
+        match state {
+            Ok(CpuState::Running) => (),
+            Ok(CpuState::Halted) => {
+                println!("CPU halted after {} frames", frame + 1);
+                break;
+            }
+            Err(err) => {
+                failure = Some(format!("{} after {} frames", err, frame));
+                break;
+            }
+        }
+        if script.as_ref().is_some_and(|host| host.stop_requested()) {
+            println!("Script stopped after {} frames", frame + 1);
+            break;
+        }
+
+        // There is no APU yet, the recorder fills the audio track with silence
+        if let Some(recorder) = &mut capture {
+            recorder.frame(&render(&cpu, &texts), &[]).unwrap_or_else(|err| fail(format!("Capture failed: {}", err)));
+        }
+    }
+
+    if let Some(recorder) = capture {
+        recorder.finish().unwrap_or_else(|err| fail(format!("Cannot finish capture: {}", err)));
+    }
+
+    if let (Some(logger), Some(path)) = (&cdl, &options.cdl) {
+        let logger = logger.borrow();
+        logger.save(path).unwrap_or_else(|err| fail(format!("Cannot save CDL {}: {}", path, err)));
+        let (code, data) = logger.prg_coverage();
+        println!("CDL: {} code and {} data bytes of {} PRG bytes", code, data, logger.prg().len());
+    }
+
+    if let Some(mut out) = trace {
+        out.flush().unwrap_or_else(|err| fail(format!("Cannot write trace: {}", err)));
+    }
+
+    if let (Some(profiler), Some(base)) = (&profiler, &options.profile) {
+        let base = Path::new(base);
+        let report = fs::File::create(base.with_extension("txt"))
+            .and_then(|file| profiler.write_report(BufWriter::new(file), region.vblank_cpu_cycles()));
+        let collapsed = fs::File::create(base.with_extension("folded"))
+            .and_then(|file| profiler.write_collapsed(BufWriter::new(file)));
+        report.and(collapsed).unwrap_or_else(|err| fail(format!("Cannot write profile {}: {}", base.display(), err)));
+    }
+
+    if let Some(message) = failure {
+        fail(message);
+    }
+
+    if let Some(path) = &options.screenshot {
+        screenshot::save_png(path, width, snake::SCREEN_HEIGHT, &render(&cpu, &texts))
+            .unwrap_or_else(|err| fail(format!("Cannot save screenshot {}: {}", path, err)));
+    }
+
+    if let (Some(path), Some(rom)) = (&options.chr, &rom) {
+        let colors = ppuview::VIEW_PALETTES[options.chr_palette];
+        let banks = ppuview::chr_banks(&rom.chr_rom);
+        let pixels: Vec<u16> = (0..banks).flat_map(|bank| ppuview::pattern_tables(&rom.chr_rom, bank, colors)).collect();
+        screenshot::save_png(path, ppuview::PATTERN_VIEW_WIDTH, banks * ppuview::PATTERN_VIEW_HEIGHT, &palette.render(&pixels))
+            .unwrap_or_else(|err| fail(format!("Cannot save pattern tables {}: {}", path, err)));
+    }
+}
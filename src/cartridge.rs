@@ -0,0 +1,109 @@
+use crate::region::Region;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_LEN: usize = 16;
+const TRAINER_LEN: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Vertical,
+    Horizontal,
+    FourScreen,
+}
+
+// Contents of an iNES file. NES 2.0 headers are read through their iNES subset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u8,
+    pub screen_mirroring: Mirroring,
+    // WRAM at $6000-$7FFF is kept alive by a battery
+    pub battery: bool,
+    // Timing named by the header, None when it does not say or the game runs on any
+    pub region: Option<Region>,
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < HEADER_LEN || raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+        let battery = raw[6] & 0b10 != 0;
+
+        let nes2 = raw[7] & 0b1100 == 0b1000;
+        let region = if nes2 {
+            match raw[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None,
+            }
+        } else if raw[9] & 1 != 0 {
+            Some(Region::Pal)
+        } else {
+            None
+        };
+
+        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let skip_trainer = raw[6] & 0b100 != 0;
+
+        let prg_rom_start = HEADER_LEN + if skip_trainer { TRAINER_LEN } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err("ROM file is truncated".to_string());
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            mapper,
+            screen_mirroring,
+            battery,
+            region,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // Builds an iNES image with the given flags 6 byte and empty PRG/CHR banks
+    pub fn test_ines(prg_pages: u8, flags6: u8) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, prg_pages, 1, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.resize(HEADER_LEN + prg_pages as usize * PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE, 0);
+        raw
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let rom = Rom::new(&test_ines(2, 0b0001_0011)).unwrap();
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.region, None);
+
+        let mut nes2 = test_ines(1, 0);
+        nes2[7] = 0b1000;
+        nes2[12] = 3;
+        assert_eq!(Rom::new(&nes2).unwrap().region, Some(Region::Dendy));
+
+        assert!(Rom::new(b"NES").is_err());
+        assert!(Rom::new(&test_ines(2, 0)[..HEADER_LEN + 100]).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::cartridge::Rom;
+use crate::cpu::{AddressingMode, CPU};
+use crate::observer::{AccessKind, ObserverId};
+use crate::opcodes;
+
+const ROM_START: u16 = 0x8000;
+const JMP_INDIRECT: u8 = 0x6C;
+
+bitflags! {
+    // One byte per PRG byte, FCEUX layout: xPdcAADC
+    pub struct PrgFlags: u8 {
+        const CODE          = 0b0000_0001;
+        const DATA          = 0b0000_0010;
+        // 8KB window the byte was mapped in when last accessed, $8000 + n * $2000
+        const BANK          = 0b0000_1100;
+        // Target of an indirect jump, JMP ($nnnn)
+        const INDIRECT_CODE = 0b0001_0000;
+        // Target of an indirect load, LDA ($nn),Y and LDA ($nn,X)
+        const INDIRECT_DATA = 0b0010_0000;
+        // Fetched by the DMC as sample data
+        const PCM_AUDIO     = 0b0100_0000;
+    }
+}
+
+bitflags! {
+    pub struct ChrFlags: u8 {
+        const RENDERED      = 0b0000_0001;
+        // Read by the CPU through PPUDATA
+        const READ          = 0b0000_0010;
+    }
+}
+
+// Code/Data Logger. The .cdl file is the PRG flags followed by the CHR flags,
+// one byte per ROM byte, as written by FCEUX.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeDataLogger {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLogger {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLogger { prg: vec![0; prg_len], chr: vec![0; chr_len] }
+    }
+
+    pub fn for_rom(rom: &Rom) -> Self {
+        CodeDataLogger::new(rom.prg_rom.len(), rom.chr_rom.len())
+    }
+
+    pub fn from_cdl(data: &[u8], rom: &Rom) -> Result<Self, String> {
+        let (prg_len, chr_len) = (rom.prg_rom.len(), rom.chr_rom.len());
+        if data.len() != prg_len + chr_len {
+            return Err(format!("CDL file is {} bytes, the ROM needs {}", data.len(), prg_len + chr_len));
+        }
+        Ok(CodeDataLogger { prg: data[..prg_len].to_vec(), chr: data[prg_len..].to_vec() })
+    }
+
+    // Continues an earlier log, or starts an empty one when the file does not exist
+    pub fn load<P: AsRef<Path>>(path: P, rom: &Rom) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => CodeDataLogger::from_cdl(&data, rom).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(CodeDataLogger::for_rom(rom)),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn to_cdl(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_cdl())
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    // Tags the PRG byte mapped at a CPU address, anything below $8000 is ignored
+    pub fn log_prg(&mut self, addr: u16, flags: PrgFlags) {
+        if addr < ROM_START || self.prg.is_empty() {
+            return;
+        }
+        let offset = (addr - ROM_START) as usize % self.prg.len();
+        let bank = (((addr - ROM_START) >> 13) as u8) << 2;
+        self.prg[offset] = (self.prg[offset] & !PrgFlags::BANK.bits()) | flags.bits() | bank;
+    }
+
+    pub fn log_chr(&mut self, addr: u16, flags: ChrFlags) {
+        if let Some(byte) = self.chr.get_mut(addr as usize) {
+            *byte |= flags.bits();
+        }
+    }
+
+    // Number of PRG bytes seen as code and as data, a byte can count as both
+    pub fn prg_coverage(&self) -> (usize, usize) {
+        let count = |flag: PrgFlags| self.prg.iter().filter(|&&byte| byte & flag.bits() != 0).count();
+        (count(PrgFlags::CODE), count(PrgFlags::DATA))
+    }
+
+    // Logs everything the CPU executes and reads from then on. Operand bytes
+    // count as code, other reads during the instruction as data.
+    pub fn attach(logger: &Rc<RefCell<CodeDataLogger>>, cpu: &mut CPU) -> ObserverId {
+        let logger = logger.clone();
+        let mut operands = 0..0;
+        let mut mode = AddressingMode::NoneAddressing;
+        let mut indirect_jump = false;
+
+        cpu.observers.add(AccessKind::READ | AccessKind::EXECUTE, 0..=0xFFFF, move |access| {
+            let mut logger = logger.borrow_mut();
+            if access.kind == AccessKind::EXECUTE {
+                let opcode = &opcodes::OPCODE_TABLE[access.value as usize];
+                let jump_flag = if indirect_jump { PrgFlags::INDIRECT_CODE } else { PrgFlags::empty() };
+                logger.log_prg(access.addr, PrgFlags::CODE | jump_flag);
+
+                let start = access.addr as u32 + 1;
+                operands = start..start + opcode.len as u32 - 1;
+                for addr in operands.clone() {
+                    logger.log_prg(addr as u16, PrgFlags::CODE);
+                }
+                mode = opcode.mode;
+                indirect_jump = access.value == JMP_INDIRECT;
+            } else if !operands.contains(&(access.addr as u32)) {
+                let flags = match mode {
+                    AddressingMode::Indirect_X | AddressingMode::Indirect_Y => PrgFlags::DATA | PrgFlags::INDIRECT_DATA,
+                    _ => PrgFlags::DATA,
+                };
+                logger.log_prg(access.addr, flags);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_ines;
+    use crate::cpu::MEM;
+
+    #[test]
+    fn test_log_code_and_data() {
+        let mut raw = test_ines(1, 0);
+        // $8000: LDA ($00),Y; JMP ($8010); $8010: .word $8020; $8020: LDA $C030; BRK
+        raw[16..21].copy_from_slice(&[0xb1, 0x00, 0x6c, 0x10, 0x80]);
+        raw[16 + 0x10..16 + 0x12].copy_from_slice(&[0x20, 0x80]);
+        raw[16 + 0x20..16 + 0x24].copy_from_slice(&[0xad, 0x30, 0xc0, 0x00]);
+        raw[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        let rom = Rom::new(&raw).unwrap();
+
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.reset();
+        cpu.mem_write_u16(0x00, 0x8040);
+
+        let logger = Rc::new(RefCell::new(CodeDataLogger::for_rom(&rom)));
+        CodeDataLogger::attach(&logger, &mut cpu);
+        cpu.run().unwrap();
+
+        let logger = logger.borrow();
+        let prg = logger.prg();
+        assert_eq!(prg[0x00], PrgFlags::CODE.bits());
+        assert_eq!(prg[0x04], PrgFlags::CODE.bits());
+        assert_eq!(prg[0x10], PrgFlags::DATA.bits());
+        assert_eq!(prg[0x20], (PrgFlags::CODE | PrgFlags::INDIRECT_CODE).bits());
+        assert_eq!(prg[0x40], (PrgFlags::DATA | PrgFlags::INDIRECT_DATA).bits());
+        // $C030 mirrors offset $30 of the 16KB bank, mapped in the third window
+        assert_eq!(prg[0x30], PrgFlags::DATA.bits() | 0b1000);
+        assert_eq!(logger.to_cdl().len(), 0x4000 + 0x2000);
+
+        let reloaded = CodeDataLogger::from_cdl(&logger.to_cdl(), &rom).unwrap();
+        assert_eq!(reloaded, *logger);
+        assert!(CodeDataLogger::from_cdl(&[0; 10], &rom).is_err());
+    }
+}
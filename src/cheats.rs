@@ -0,0 +1,284 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Game Genie letters, each standing for its index
+const GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+const ROM_START: u16 = 0x8000;
+
+#[derive(Debug)]
+pub enum CheatError {
+    Io(io::Error),
+    InvalidCode(String),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::Io(err) => write!(f, "{}", err),
+            CheatError::InvalidCode(code) => write!(f, "invalid cheat code {:?}", code),
+            CheatError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+impl From<io::Error> for CheatError {
+    fn from(err: io::Error) -> Self {
+        CheatError::Io(err)
+    }
+}
+
+// A patch of a single byte. With `compare` set the patch only applies
+// while the ROM holds that value, which keeps it off other banks.
+// Raw codes below $8000 freeze RAM: reads always see `value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub code: String,
+    pub description: String,
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    // Accepts 6 or 8 letter Game Genie codes and raw `addr:value[:compare]` hex codes
+    pub fn parse(code: &str) -> Result<Cheat, CheatError> {
+        let code = code.trim();
+        let (address, value, compare) = if code.contains(':') {
+            decode_raw(code)
+        } else {
+            decode_game_genie(code)
+        }
+        .ok_or_else(|| CheatError::InvalidCode(code.to_string()))?;
+
+        Ok(Cheat {
+            code: code.to_uppercase(),
+            description: String::new(),
+            address,
+            value,
+            compare,
+            enabled: true,
+        })
+    }
+
+    fn applies(&self, addr: u16, value: u8) -> bool {
+        self.enabled && self.address == addr && self.compare.is_none_or(|compare| compare == value)
+    }
+}
+
+fn decode_game_genie(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    let n = code
+        .bytes()
+        .map(|c| GENIE_LETTERS.iter().position(|&letter| letter == c.to_ascii_uppercase()).map(|n| n as u16))
+        .collect::<Option<Vec<u16>>>()?;
+    if n.len() != 6 && n.len() != 8 {
+        return None;
+    }
+
+    let address = ROM_START
+        + (((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8));
+
+    // The last letter's high bit moves into the compare value for 8 letter codes
+    let (value, compare) = if n.len() == 6 {
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[5] & 8);
+        (value, None)
+    } else {
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[7] & 8);
+        let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+        (value, Some(compare as u8))
+    };
+    Some((address, value as u8, compare))
+}
+
+fn decode_raw(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    let parts: Vec<&str> = code.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return None;
+    }
+
+    let address = u16::from_str_radix(parts[0].trim_start_matches('$'), 16).ok()?;
+    let value = u8::from_str_radix(parts[1], 16).ok()?;
+    let compare = match parts.get(2) {
+        Some(compare) => Some(u8::from_str_radix(compare, 16).ok()?),
+        None => None,
+    };
+    Some((address, value, compare))
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+    pub fn new() -> Self {
+        CheatList { cheats: Vec::new() }
+    }
+
+    pub fn add(&mut self, cheat: Cheat) -> usize {
+        self.cheats.push(cheat);
+        self.cheats.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Cheat {
+        self.cheats.remove(index)
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(cheat) = self.cheats.get_mut(index) {
+            cheat.enabled = enabled;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    // Value seen on the bus when `value` is read from `addr`
+    pub fn patch(&self, addr: u16, value: u8) -> u8 {
+        self.cheats
+            .iter()
+            .find(|cheat| cheat.applies(addr, value))
+            .map_or(value, |cheat| cheat.value)
+    }
+
+    // One code per line followed by an optional description.
+    // Lines starting with '#' are comments, a leading '-' marks a disabled code.
+    pub fn from_text(text: &str) -> Result<CheatList, CheatError> {
+        let mut list = CheatList::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(rest) => (false, rest),
+                None => (true, line),
+            };
+            let (code, description) = match line.find(char::is_whitespace) {
+                Some(split) => (&line[..split], line[split..].trim()),
+                None => (line, ""),
+            };
+
+            let mut cheat = Cheat::parse(code).map_err(|err| CheatError::Parse {
+                line: idx + 1,
+                message: err.to_string(),
+            })?;
+            cheat.description = description.to_string();
+            cheat.enabled = enabled;
+            list.add(cheat);
+        }
+        Ok(list)
+    }
+
+    pub fn to_text(&self) -> String {
+        self.cheats
+            .iter()
+            .map(|cheat| {
+                let prefix = if cheat.enabled { "" } else { "-" };
+                if cheat.description.is_empty() {
+                    format!("{}{}\n", prefix, cheat.code)
+                } else {
+                    format!("{}{} {}\n", prefix, cheat.code, cheat.description)
+                }
+            })
+            .collect()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<CheatList, CheatError> {
+        CheatList::from_text(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CheatError> {
+        fs::write(path, self.to_text())?;
+        Ok(())
+    }
+}
+
+// Cheats for `game.nes` live next to it in `game.cht`
+pub fn cheat_path_for_rom<P: AsRef<Path>>(rom: P) -> PathBuf {
+    rom.as_ref().with_extension("cht")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_six_letter_code() {
+        let cheat = Cheat::parse("sxiopo").unwrap();
+        assert_eq!(cheat.code, "SXIOPO");
+        assert_eq!(cheat.address, 0x91D9);
+        assert_eq!(cheat.value, 0xAD);
+        assert_eq!(cheat.compare, None);
+    }
+
+    #[test]
+    fn test_decode_eight_letter_code() {
+        let cheat = Cheat::parse("YEUZUGAA").unwrap();
+        assert_eq!(cheat.address, 0xACB3);
+        assert_eq!(cheat.value, 0x07);
+        assert_eq!(cheat.compare, Some(0x00));
+    }
+
+    #[test]
+    fn test_decode_raw_code() {
+        let cheat = Cheat::parse("$91D9:ad:10").unwrap();
+        assert_eq!((cheat.address, cheat.value, cheat.compare), (0x91D9, 0xAD, Some(0x10)));
+
+        let freeze = Cheat::parse("0075:09").unwrap();
+        assert_eq!((freeze.address, freeze.value, freeze.compare), (0x0075, 0x09, None));
+
+        assert!(Cheat::parse("10000:10").is_err());
+        assert!(Cheat::parse("SXIOP").is_err());
+        assert!(Cheat::parse("SXIOPB").is_err());
+    }
+
+    #[test]
+    fn test_patch_respects_compare_and_enabled() {
+        let mut list = CheatList::new();
+        list.add(Cheat::parse("9000:EA").unwrap());
+        let compared = list.add(Cheat::parse("9001:EA:4C").unwrap());
+
+        assert_eq!(list.patch(0x9000, 0x20), 0xEA);
+        assert_eq!(list.patch(0x9001, 0x20), 0x20);
+        assert_eq!(list.patch(0x9001, 0x4C), 0xEA);
+        assert_eq!(list.patch(0x9002, 0x4C), 0x4C);
+
+        list.set_enabled(compared, false);
+        assert_eq!(list.patch(0x9001, 0x4C), 0x4C);
+    }
+
+    #[test]
+    fn test_cheat_file_round_trip() {
+        let text = "# Super Mario Bros.\nSXIOPO Infinite lives\n-9000:EA\n";
+        let list = CheatList::from_text(text).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.iter().next().unwrap().description, "Infinite lives");
+        assert!(!list.iter().nth(1).unwrap().enabled);
+        assert_eq!(CheatList::from_text(&list.to_text()).unwrap(), list);
+
+        assert!(matches!(CheatList::from_text("SXIOPO\nBAD\n"), Err(CheatError::Parse { line: 2, .. })));
+        assert_eq!(cheat_path_for_rom("roms/smb.nes"), PathBuf::from("roms/smb.cht"));
+    }
+}
@@ -1,5 +1,11 @@
+use crate::cartridge::Rom;
+use crate::cheats::CheatList;
+use crate::fourscore::{FourScore, Multitap};
+use crate::joypad::Joypad;
+use crate::observer::{AccessKind, MemAccess, MemObservers};
+use crate::zapper::{PortDevice, Zapper};
 use crate::opcodes;
-use std::collections::HashMap;
+use std::fmt;
 
 bitflags! {
     // Status registers
@@ -27,8 +33,20 @@ bitflags! {
 
 const STACK         : u16   = 0x0100;
 const STACK_RESET   : u8    = 0xFD;
-
-#[derive(Debug)]
+const NMI_VECTOR    : u16   = 0xFFFA;
+const RESET_VECTOR  : u16   = 0xFFFC;
+const PROGRAM_START : u16   = 0x0600;
+const JOYPAD_1      : u16   = 0x4016;
+const JOYPAD_2      : u16   = 0x4017;
+const PRG_RAM_START : u16   = 0x6000;
+const ROM_START     : u16   = 0x8000;
+
+const SAVE_STATE_MAGIC      : &[u8; 4]  = b"NESS";
+const SAVE_STATE_VERSION    : u8        = 1;
+const SAVE_STATE_LEN        : usize     = 4 + 1 + 5 + 2 + 8 + 2 + 0x10000;
+const NMI_CYCLES    : u16   = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -50,7 +68,72 @@ pub struct CPU {
     pub status: CPUFlags,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    memory: [u8; 0xFFFF]
+    pub cycles: u64,
+    jammed: bool,
+    nmi_pending: bool,
+    pub joypad1: Joypad,
+    pub joypad2: Joypad,
+    // Only read through a multitap
+    pub joypad3: Joypad,
+    pub joypad4: Joypad,
+    pub multitap: Multitap,
+    four_score: FourScore,
+    pub zapper: Zapper,
+    // Decides whether $4017 reads joypad2 or the zapper
+    pub port2: PortDevice,
+    pub cheats: CheatList,
+    pub observers: MemObservers,
+    // Opcode address of the instruction being executed, reported to observers
+    instruction_pc: u16,
+    // Boxed so a whole CPU can be moved or swapped cheaply
+    memory: Box<[u8; 0x10000]>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    Running,
+    // BRK was executed
+    Halted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownOpcode { pc: u16, byte: u8 },
+    // A JAM opcode locked up the CPU, only a reset recovers it
+    Jammed { pc: u16, byte: u8 },
+    UnsupportedAddressing { pc: u16, mode: AddressingMode },
+    ProgramTooLarge { len: usize, max: usize },
+    InvalidSaveState,
+    // Only NROM fits in the flat address space, bank switching is not emulated
+    UnsupportedMapper(u8),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { pc, byte } =>
+                write!(f, "unknown opcode {:#04x} at {:#06x}", byte, pc),
+            CpuError::Jammed { pc, byte } =>
+                write!(f, "CPU jammed by opcode {:#04x} at {:#06x}", byte, pc),
+            CpuError::UnsupportedAddressing { pc, mode } =>
+                write!(f, "addressing mode {:?} not supported by instruction at {:#06x}", mode, pc),
+            CpuError::ProgramTooLarge { len, max } =>
+                write!(f, "program of {} bytes does not fit in {} bytes of memory", len, max),
+            CpuError::InvalidSaveState => write!(f, "save state is corrupt or from another version"),
+            CpuError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+// Outcome of executing a single instruction with `CPU::step`
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    pub opcode: &'static opcodes::OpCode,
+    pub cycles: u16,
+    pub state: CpuState,
+    pub interrupt_serviced: bool,
 }
 
 pub trait MEM {
@@ -60,24 +143,47 @@ pub trait MEM {
 
     fn mem_read_u16(&self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | lo
     }
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xFF) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 }
 
 impl MEM for CPU {
     fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+        let value = self.bus_read(addr);
+        if self.observers.watches(AccessKind::READ) {
+            self.notify_observers(AccessKind::READ, addr, value);
+        }
+        value
     }
 
     fn mem_write(&mut self, addr: u16, value: u8) {
-        self.memory[addr as usize] = value;
+        if self.observers.watches(AccessKind::WRITE) {
+            self.notify_observers(AccessKind::WRITE, addr, value);
+        }
+        match addr {
+            // The strobe line is shared by both controller ports
+            JOYPAD_1 => {
+                self.joypad1.write(value);
+                self.joypad2.write(value);
+                self.joypad3.write(value);
+                self.joypad4.write(value);
+                self.four_score.write(value);
+            }
+            _ => self.memory[addr as usize] = value,
+        }
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -97,39 +203,101 @@ impl CPU {
             stack_pointer: STACK_RESET,
             program_counter: 0,
             status: CPUFlags::from_bits_truncate(0b100100),
-            memory: [0; 0xFFFF],
+            cycles: 0,
+            jammed: false,
+            nmi_pending: false,
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            joypad3: Joypad::new(),
+            joypad4: Joypad::new(),
+            multitap: Multitap::Off,
+            four_score: FourScore::new(),
+            zapper: Zapper::new(),
+            port2: PortDevice::Joypad,
+            cheats: CheatList::new(),
+            observers: MemObservers::new(),
+            instruction_pc: 0,
+            memory: Box::new([0; 0x10000]),
+        }
+    }
+
+    fn bus_read(&self, addr: u16) -> u8 {
+        match addr {
+            JOYPAD_1 => self.read_port(0),
+            JOYPAD_2 if self.port2 == PortDevice::Zapper => self.zapper.read(),
+            JOYPAD_2 => self.read_port(1),
+            // ROM patches and RAM freezes both act on the value being read
+            _ if !self.cheats.is_empty() => self.cheats.patch(addr, self.memory[addr as usize]),
+            _ => self.memory[addr as usize],
         }
     }
 
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
-        match mode {
+    fn read_port(&self, port: usize) -> u8 {
+        let (first, second) = match port {
+            0 => (&self.joypad1, &self.joypad3),
+            _ => (&self.joypad2, &self.joypad4),
+        };
+        match self.multitap {
+            Multitap::Off => first.read(),
+            Multitap::FourScore => self.four_score.read(port, first, second),
+            Multitap::Famicom => first.read() | (second.read() << 1),
+        }
+    }
+
+    // Players 1 to 4 counted from 0
+    pub fn joypad_mut(&mut self, player: usize) -> Option<&mut Joypad> {
+        match player {
+            0 => Some(&mut self.joypad1),
+            1 => Some(&mut self.joypad2),
+            2 => Some(&mut self.joypad3),
+            3 => Some(&mut self.joypad4),
+            _ => None,
+        }
+    }
+
+    // Memory as a debugger sees it: observers are not told and controllers are not clocked
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            JOYPAD_1 | JOYPAD_2 => self.memory[addr as usize],
+            _ => self.bus_read(addr),
+        }
+    }
+
+    pub fn peek_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))])
+    }
+
+    #[cold]
+    fn notify_observers(&self, kind: AccessKind, addr: u16, value: u8) {
+        let access = MemAccess { kind, addr, value, cycle: self.cycles, pc: self.instruction_pc };
+        self.observers.notify(&access);
+    }
+
+    fn get_operand_address(&self, mode: &AddressingMode) -> Result<u16, CpuError> {
+        let addr = match mode {
             AddressingMode::Immediate => self.program_counter,
             AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
             AddressingMode::ZeroPage_X => {
                 let pos = self.mem_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                addr
+                pos.wrapping_add(self.register_x) as u16
             },
             AddressingMode::ZeroPage_Y => {
                 let pos = self.mem_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                addr
+                pos.wrapping_add(self.register_y) as u16
             },
             AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
             AddressingMode::Absolute_X => {
                 let base = self.mem_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_x as u16);
-                addr
+                base.wrapping_add(self.register_x as u16)
             },
             AddressingMode::Absolute_Y => {
                 let base = self.mem_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_y as u16);
-                addr
+                base.wrapping_add(self.register_y as u16)
             },
             AddressingMode::Indirect_X => {
                 let base = self.mem_read(self.program_counter);
 
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
+                let ptr: u8 = base.wrapping_add(self.register_x);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
                 (hi as u16) << 8 | lo as u16
@@ -137,88 +305,102 @@ impl CPU {
             AddressingMode::Indirect_Y => {
                 let base = self.mem_read(self.program_counter);
                 let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                deref
+                deref_base.wrapping_add(self.register_y as u16)
             },
-            AddressingMode::NoneAddressing => panic!("mode {:?} not supported", mode),
-        }
+            AddressingMode::NoneAddressing => {
+                return Err(CpuError::UnsupportedAddressing {
+                    pc: self.program_counter.wrapping_sub(1),
+                    mode: *mode,
+                });
+            }
+        };
+        Ok(addr)
     }
 
-    fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn ldy(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         self.register_y = data;
         self.update_zero_and_negative_flags(self.register_y);
+        Ok(())
     }
 
-    fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn ldx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         self.register_x = data;
         self.update_zero_and_negative_flags(self.register_y);
+        Ok(())
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn lda(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let value = self.mem_read(addr);
         self.set_register_a(value);
+        Ok(())
     }
 
-    fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn sta(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         self.mem_write(addr, self.register_a);
+        Ok(())
     }
 
-    fn and(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn and(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         self.set_register_a(data & self.register_a);
+        Ok(())
     }
 
-    fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn eor(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         self.set_register_a(data ^ self.register_a);
+        Ok(())
     }
 
-    fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn ora(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         self.set_register_a(data | self.register_a);
+        Ok(())
     }
 
-    fn tax(&mut self) {
+    pub(crate) fn tax(&mut self) {
         self.register_x = self.register_a;
         self.update_zero_and_negative_flags(self.register_x);
     }
 
-    fn inx(&mut self) {
+    pub(crate) fn inx(&mut self) {
         self.register_x = self.register_x.wrapping_add(1);
         self.update_zero_and_negative_flags(self.register_x);
     }
 
-    fn iny(&mut self) {
+    pub(crate) fn iny(&mut self) {
         self.register_y = self.register_y.wrapping_add(1);
         self.update_zero_and_negative_flags(self.register_y);
     }
 
-    fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn sbc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        Ok(())
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);   
+    pub(crate) fn adc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;   
         let value = self.mem_read(addr);
         self.add_to_register_a(value);
+        Ok(())
     }
 
     fn stack_pop(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
-        self.mem_read((STACK as u16) + self.stack_pointer as u16)
+        self.mem_read(STACK + self.stack_pointer as u16)
     }
 
     fn stack_pop_u16(&mut self) -> u16 {
@@ -227,8 +409,8 @@ impl CPU {
         hi << 8 | lo
     }
 
-    fn stack_push(&mut self, data: u8) {
-        self.mem_write((STACK as u16) + self.stack_pointer as u16, data);
+    pub(crate) fn stack_push(&mut self, data: u8) {
+        self.mem_write(STACK + self.stack_pointer as u16, data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1)
     }
 
@@ -258,7 +440,7 @@ impl CPU {
         self.set_register_a(result);
     }
 
-    fn asl_accumulator(&mut self) {
+    pub(crate) fn asl_accumulator(&mut self) {
         let mut data = self.register_a;
 
         if data >> 7 == 1 { self.set_carry_flag(); } 
@@ -268,8 +450,8 @@ impl CPU {
         self.set_register_a(data);
     }
 
-    fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn asl(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let mut data =self.mem_read(addr);
 
         if data >> 7 == 1 { self.set_carry_flag(); } 
@@ -278,10 +460,10 @@ impl CPU {
         data <<= 1 ;
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn lsr_accumulator(&mut self) {
+    pub(crate) fn lsr_accumulator(&mut self) {
         let mut data =self.register_a;
 
         if data & 1 == 1 { self.set_carry_flag(); } 
@@ -291,8 +473,8 @@ impl CPU {
         self.set_register_a(data);
     }
 
-    fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn lsr(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let mut data = self.mem_read(addr);
 
         if data & 1 == 1    { self.set_carry_flag(); } 
@@ -301,11 +483,11 @@ impl CPU {
         data >>= 1 ;
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn rol(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let mut data = self.mem_read(addr);
         let old_carry = self.status.contains(CPUFlags::CARRY);  
 
@@ -316,10 +498,10 @@ impl CPU {
         if old_carry { data |= 1; }
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn rol_accumulator(&mut self) {
+    pub(crate) fn rol_accumulator(&mut self) {
         let mut data  = self.register_a;
         let old_carry = self.status.contains(CPUFlags::CARRY);
 
@@ -331,8 +513,8 @@ impl CPU {
         self.set_register_a(data);
     }
 
-    fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn ror(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let mut data = self.mem_read(addr);
         let old_carry = self.status.contains(CPUFlags::CARRY);
 
@@ -343,10 +525,10 @@ impl CPU {
         if old_carry { data |= 0b1000_0000; }
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn ror_accumulator(&mut self) {
+    pub(crate) fn ror_accumulator(&mut self) {
         let mut data = self.register_a;
         let old_carry = self.status.contains(CPUFlags::CARRY);
 
@@ -358,54 +540,54 @@ impl CPU {
         self.set_register_a(data);
     }
 
-    fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn inc(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let mut data = self.mem_read(addr);
         data = data.wrapping_add(1);
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn dey(&mut self) {
+    pub(crate) fn dey(&mut self) {
         self.register_y = self.register_y.wrapping_sub(1);
         self.update_zero_and_negative_flags(self.register_y);
     }
 
-    fn dex(&mut self) {
+    pub(crate) fn dex(&mut self) {
         self.register_x = self.register_x.wrapping_sub(1);
         self.update_zero_and_negative_flags(self.register_x);
     }
 
-    fn dec(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn dec(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let mut data = self.mem_read(addr);
         data = data.wrapping_sub(1);
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn pla(&mut self) { 
+    pub(crate) fn pla(&mut self) { 
         let data = self.stack_pop();
         self.set_register_a(data);
     }
 
-    fn plp(&mut self) {
+    pub(crate) fn plp(&mut self) {
         self.status.bits = self.stack_pop();
         self.status.remove(CPUFlags::BREAK);
         self.status.remove(CPUFlags::UNUSED); 
     }
 
-    fn php(&mut self) {
-        let mut flags = self.status.clone();
+    pub(crate) fn php(&mut self) {
+        let mut flags = self.status;
         flags.insert(CPUFlags::BREAK);
         flags.insert(CPUFlags::UNUSED);
         self.stack_push(flags.bits());
     }
 
-    fn bit(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn bit(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
         let and = self.register_a & data;
 
@@ -414,19 +596,21 @@ impl CPU {
 
         self.status.set(CPUFlags::NEGATIVE, data & 0b1000_0000 > 0);
         self.status.set(CPUFlags::OVERFLOW, data & 0b0100_0000 > 0);
+        Ok(())
     }
 
-    fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn compare(&mut self, mode: &AddressingMode, compare_with: u8) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
         let data = self.mem_read(addr);
 
         if data <= compare_with { self.set_carry_flag(); }
         else                    { self.clear_carry_flag(); }
 
         self.update_zero_and_negative_flags(compare_with.wrapping_sub(data));
+        Ok(())
     }
 
-    fn branch(&mut self, condition: bool) {
+    pub(crate) fn branch(&mut self, condition: bool) {
         if condition {
             let jump = self.mem_read(self.program_counter) as i8;
             let jump_addr = self
@@ -438,205 +622,312 @@ impl CPU {
         }
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub(crate) fn stx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.register_x);
+        Ok(())
+    }
+
+    pub(crate) fn sty(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.register_y);
+        Ok(())
+    }
+
+    pub(crate) fn tay(&mut self) {
+        self.register_y = self.register_a;
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    pub(crate) fn tsx(&mut self) {
+        self.register_x = self.stack_pointer;
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    pub(crate) fn txa(&mut self) {
+        self.register_a = self.register_x;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    pub(crate) fn txs(&mut self) {
+        self.stack_pointer = self.register_x;
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    pub(crate) fn tya(&mut self) {
+        self.register_a = self.register_y;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    pub(crate) fn jmp_absolute(&mut self) {
+        let mem_address = self.mem_read_u16(self.program_counter);
+        self.program_counter = mem_address;
+    }
+
+    pub(crate) fn jmp_indirect(&mut self) {
+        let mem_address = self.mem_read_u16(self.program_counter);
+        // 6502 bug moed with the page boundary
+        // if adress $3000 contains $40, $30FF contains $80 and $3100 contains $50
+        // the result of JMP ($30FF) will be a transfer of control to $4080 than $5080
+        // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+        let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+            let lo = self.mem_read(mem_address);
+            let hi = self.mem_read(mem_address & 0xFF00);
+            (hi as u16) << 8 | lo as u16
+        } else {
+            self.mem_read_u16(mem_address)
+        };
+
+        self.program_counter = indirect_ref;
+    }
+
+    pub(crate) fn jsr(&mut self) {
+        self.stack_push_u16(self.program_counter.wrapping_add(1));
+        let target_address = self.mem_read_u16(self.program_counter);
+        self.program_counter = target_address
+    }
+
+    pub(crate) fn rts(&mut self) {
+        self.program_counter = self.stack_pop_u16().wrapping_add(1);
+    }
+
+    pub(crate) fn rti(&mut self) {
+        self.status.bits = self.stack_pop();
+        self.status.remove(CPUFlags::BREAK);
+        self.status.insert(CPUFlags::UNUSED);
+
+        self.program_counter = self.stack_pop_u16();
+    }
+
+    pub(crate) fn unknown_opcode(&mut self, _mode: &AddressingMode) -> Result<(), CpuError> {
+        let pc = self.program_counter.wrapping_sub(1);
+        Err(CpuError::UnknownOpcode { pc, byte: self.mem_read(pc) })
+    }
+
+    pub(crate) fn jam(&mut self, _mode: &AddressingMode) -> Result<(), CpuError> {
+        // The CPU stops fetching until the next reset
+        self.jammed = true;
+        let pc = self.program_counter.wrapping_sub(1);
+        Err(CpuError::Jammed { pc, byte: self.mem_read(pc) })
+    }
+
+    // Latch an NMI, serviced before the next instruction is fetched
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    pub fn nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    fn service_nmi(&mut self) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags = self.status;
+        flags.remove(CPUFlags::BREAK);
+        flags.insert(CPUFlags::UNUSED);
+        self.stack_push(flags.bits());
+        self.status.insert(CPUFlags::INTERRUPT_DISABLE);
+
+        self.program_counter = self.mem_read_u16(NMI_VECTOR);
+    }
+
+    // On error PC is left on the offending instruction so the state can be inspected
+    pub fn step(&mut self) -> Result<Step, CpuError> {
+        let opcodes: &'static [opcodes::OpCode; 256] = &opcodes::OPCODE_TABLE;
+
+        if self.jammed {
+            let pc = self.program_counter;
+            return Err(CpuError::Jammed { pc, byte: self.mem_read(pc) });
+        }
+
+        let mut cycles = 0;
+        let interrupt_serviced = self.nmi_pending;
+        if interrupt_serviced {
+            self.nmi_pending = false;
+            self.service_nmi();
+            cycles += NMI_CYCLES;
+        }
+
+        let instruction_start = self.program_counter;
+        self.instruction_pc = instruction_start;
+        let code = self.bus_read(self.program_counter);
+        if self.observers.watches(AccessKind::EXECUTE) {
+            self.notify_observers(AccessKind::EXECUTE, instruction_start, code);
+        }
+        self.program_counter = self.program_counter.wrapping_add(1);
+        let program_counter_state = self.program_counter;
+
+        let opcode = &opcodes[code as usize];
+        if let Err(err) = (opcode.handler)(self, &opcode.mode) {
+            self.program_counter = instruction_start;
+            return Err(err);
+        }
+
+        if program_counter_state == self.program_counter {
+            self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
+        }
+
+        // Base cycles only, page crossing and taken branch penalties are not counted yet
+        cycles += opcode.cycles as u16;
+        self.cycles += cycles as u64;
+
+        let state = if code == 0x00 /* BRK */ {
+            CpuState::Halted
+        } else {
+            CpuState::Running
+        };
+
+        Ok(Step { opcode, cycles, state, interrupt_serviced })
+    }
+
+    // Runs until at least `cycles` more cycles have elapsed or the CPU stops
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<CpuState, CpuError> {
+        self.run_for_cycles_with_callback(cycles, |_| {})
+    }
+
+    // Like run_for_cycles, calling `callback` before every instruction
+    pub fn run_for_cycles_with_callback<F>(&mut self, cycles: u64, mut callback: F) -> Result<CpuState, CpuError>
     where F: FnMut(&mut CPU) {
-        let ref opcodes: HashMap<u8, &'static opcodes:: OpCode> = 
-            *opcodes::OPCODES_MAP;
+        self.run_for_cycles_while(cycles, |cpu| {
+            callback(cpu);
+            true
+        })
+    }
+
+    // Like run_for_cycles_with_callback, stopping early before the instruction
+    // for which `callback` returns false. The CPU is left running in that case.
+    pub fn run_for_cycles_while<F>(&mut self, cycles: u64, mut callback: F) -> Result<CpuState, CpuError>
+    where F: FnMut(&mut CPU) -> bool {
+        let target = self.cycles + cycles;
+        while self.cycles < target {
+            if !callback(self) {
+                break;
+            }
+            let step = self.step()?;
+            if step.state != CpuState::Running {
+                return Ok(step.state);
+            }
+        }
+        Ok(CpuState::Running)
+    }
+
+    // Runs until `predicate` holds before the next instruction or the CPU stops
+    pub fn run_until<P>(&mut self, mut predicate: P) -> Result<CpuState, CpuError>
+    where P: FnMut(&CPU) -> bool {
+        while !predicate(self) {
+            let step = self.step()?;
+            if step.state != CpuState::Running {
+                return Ok(step.state);
+            }
+        }
+        Ok(CpuState::Running)
+    }
 
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        self.run_with_callback(|_| {})
+    }
+
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), CpuError>
+    where F: FnMut(&mut CPU) {
         loop {
             callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-
-            let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} is not recognzed.", code));
-
-            match code {
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
-                    self.lda(&opcode.mode);
-                }
-                0xAA => self.tax(),
-                0xE8 => self.inx(),
-                0x00 => return,
-                /* CLD */ 0xD8 => self.status.remove(CPUFlags::DECIMAL_MODE),
-                /* CLI */ 0x58 => self.status.remove(CPUFlags::INTERRUPT_DISABLE),
-                /* CLV */ 0xB8 => self.status.remove(CPUFlags::OVERFLOW),
-                /* CLC */ 0x18 => self.clear_carry_flag(),
-                /* SEC */ 0x38 => self.set_carry_flag(),
-                /* SEI */ 0x78 => self.status.insert(CPUFlags::INTERRUPT_DISABLE),
-                /* SED */ 0xF8 => self.status.insert(CPUFlags::DECIMAL_MODE),
-                /* PHA */ 0x48 => self.stack_push(self.register_a),
-                /* PLA */ 0x68 => self.pla(),
-                /* PHP */ 0x08 => self.php(),
-                /* PLP */ 0x28 => self.plp(),
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                /* SBC */
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode),
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                /* LSR */
-                0x4A => self.lsr_accumulator(),
-                0x46 | 0x56 | 0x4E | 0x5E => { 
-                    self.lsr(&opcode.mode); 
-                }
-                /* ASL */ 
-                0x0A => self.asl_accumulator(),
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&opcode.mode);
-                }
-                /* ROL */
-                0x2A => self.rol_accumulator(),
-                0x26 | 0x36 | 0x2E | 0x3E => {
-                    self.rol(&opcode.mode);
-                }
-                /* ROR */
-                0x6A => self.ror_accumulator(),
-                0x66 | 0x76 | 0x6E | 0x7E => {
-                    self.ror(&opcode.mode);
-                }
-                /* INC */
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&opcode.mode);
-                }
-                /* INY */ 0xC8 => self.iny(),
-                /* DEC */ 
-                0xc6 | 0xD6 | 0xCE | 0xDE => {
-                    self.dec(&opcode.mode);
-                }
-                /* DEX */ 0xCA => self.dex(),
-                /* DEY */ 0x88 => self.dey(),
-                /* CMP */
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => 
-                    self.compare(&opcode.mode, self.register_a),
-                /* CPY */
-                0xC0 | 0xC4 | 0xCC => 
-                    self.compare(&opcode.mode, self.register_y),
-                /* CPX */
-                0xE0 | 0xE4 | 0xEC => 
-                    self.compare(&opcode.mode, self.register_x),
-                /* JMP Absolute */ 0x4C =>{
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
-                /* JMP Indirect */ 0x6C => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    // 6502 bug moed with the page boundary
-                    // if adress $3000 contains $40, $30FF contains $80 and $3100 contains $50
-                    // the result of JMP ($30FF) will be a transfer of control to $4080 than $5080
-                    // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | lo as u16
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.program_counter = indirect_ref;
-                }
-                /* JSR */ 0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
-                /* RTS */ 0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
-                /* RTI */ 0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(CPUFlags::BREAK);
-                    self.status.insert(CPUFlags::UNUSED);
-
-                    self.program_counter = self.stack_pop_u16();
-                }
-                /* BNE */ 0xD0 => {
-                    self.branch(!self.status.contains(CPUFlags::ZERO));
-                }
-                /* BVS */ 0x70 => {
-                    self.branch(self.status.contains(CPUFlags::OVERFLOW));
-                }
-                /* BVC */ 0x50 => {
-                    self.branch(!self.status.contains(CPUFlags::OVERFLOW));
-                }
-                /* BPL */ 0x10 => {
-                    self.branch(!self.status.contains(CPUFlags::NEGATIVE));
-                }
-                /* BMI */ 0x30 => {
-                    self.branch(self.status.contains(CPUFlags::NEGATIVE));
-                }
-                /* BEQ */ 0xF0 => {
-                    self.branch(self.status.contains(CPUFlags::ZERO));  
-                }
-                /* BCS */ 0xB0 => {
-                    self.branch(self.status.contains(CPUFlags::CARRY));
-                }
-                /* BCC */ 0x90 => {
-                    self.branch(!self.status.contains(CPUFlags::CARRY));
-                }
-                /* BIT */ 0x24 | 0x2C => self.bit(&opcode.mode),
-                /* STA */ 0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
-                /* STX */ 0x86 | 0x96 | 0x8E => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                }
-                /* STY */ 0x84 | 0x94 | 0x8C => {
-                    let addr = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                }
-                /* LDX */ 0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
-                /* LDY */ 0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
-                /* NOP */ 0xEA => () /* Do nothing */,
-                /* TAY */ 0xA8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
-                /* TSX */ 0xBA => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                /* TXA */ 0x8A => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                /* TXS */ 0x9A => {
-                    self.stack_pointer = self.register_x;
-                }
-                /* TYA */ 0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                _ => todo!(),
+            if self.step()?.state != CpuState::Running {
+                return Ok(());
             }
+        }
+    }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
-            
-            }
+    pub fn load(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
+        let start = PROGRAM_START as usize;
+        let max = RESET_VECTOR as usize - start;
+        if program.len() > max {
+            return Err(CpuError::ProgramTooLarge { len: program.len(), max });
+        }
+
+        self.memory[start..(start + program.len())].copy_from_slice(&program[..]);
+        self.mem_write_u16(RESET_VECTOR, PROGRAM_START);
+        Ok(())
+    }
+
+    // Maps NROM PRG into $8000-$FFFF, a 16KB bank is mirrored at $C000
+    pub fn load_rom(&mut self, rom: &Rom) -> Result<(), CpuError> {
+        if rom.mapper != 0 {
+            return Err(CpuError::UnsupportedMapper(rom.mapper));
+        }
+        let max = 0x10000 - ROM_START as usize;
+        if rom.prg_rom.is_empty() || rom.prg_rom.len() > max {
+            return Err(CpuError::ProgramTooLarge { len: rom.prg_rom.len(), max });
         }
+
+        for bank in self.memory[ROM_START as usize..].chunks_mut(rom.prg_rom.len()) {
+            bank.copy_from_slice(&rom.prg_rom[..bank.len()]);
+        }
+        Ok(())
+    }
+
+    // Cartridge WRAM at $6000-$7FFF, the part a battery keeps across power cycles
+    pub fn save_ram(&self) -> &[u8] {
+        &self.memory[PRG_RAM_START as usize..ROM_START as usize]
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x0600..(0x0600 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xFFFC, 0x0600);
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        let ram = &mut self.memory[PRG_RAM_START as usize..ROM_START as usize];
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
+        self.load(program)?;
         self.reset();
-        self.run();
+        self.run()
+    }
+
+    // Registers, pending interrupts and the whole address space.
+    // Controllers are not part of the state, input is replayed from outside.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(SAVE_STATE_LEN);
+        state.extend_from_slice(SAVE_STATE_MAGIC);
+        state.push(SAVE_STATE_VERSION);
+        state.extend_from_slice(&[
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status.bits(),
+            self.stack_pointer,
+        ]);
+        state.extend_from_slice(&self.program_counter.to_le_bytes());
+        state.extend_from_slice(&self.cycles.to_le_bytes());
+        state.push(self.jammed as u8);
+        state.push(self.nmi_pending as u8);
+        state.extend_from_slice(&self.memory[..]);
+        state
+    }
+
+    // Whether `state` is a save state this version of the emulator can load
+    pub fn is_save_state(state: &[u8]) -> bool {
+        state.len() == SAVE_STATE_LEN
+            && &state[0..4] == SAVE_STATE_MAGIC
+            && state[4] == SAVE_STATE_VERSION
     }
 
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), CpuError> {
+        if !CPU::is_save_state(state) {
+            return Err(CpuError::InvalidSaveState);
+        }
+
+        self.register_a = state[5];
+        self.register_x = state[6];
+        self.register_y = state[7];
+        self.status = CPUFlags::from_bits_truncate(state[8]);
+        self.stack_pointer = state[9];
+        self.program_counter = u16::from_le_bytes([state[10], state[11]]);
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&state[12..20]);
+        self.cycles = u64::from_le_bytes(cycles);
+        self.jammed = state[20] != 0;
+        self.nmi_pending = state[21] != 0;
+        self.memory.copy_from_slice(&state[22..]);
+        Ok(())
+    }
 
     pub fn reset(&mut self) {
         self.register_a = 0;
@@ -644,15 +935,18 @@ impl CPU {
         self.register_y = 0;
         self.stack_pointer = STACK_RESET;
         self.status = CPUFlags::from_bits_truncate(0b100100);
+        self.cycles = 0;
+        self.jammed = false;
+        self.nmi_pending = false;
 
-        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.program_counter = self.mem_read_u16(RESET_VECTOR);
     }
 
-    fn set_carry_flag(&mut self) {
+    pub(crate) fn set_carry_flag(&mut self) {
         self.status.insert(CPUFlags::CARRY)
     }
 
-    fn clear_carry_flag(&mut self) {
+    pub(crate) fn clear_carry_flag(&mut self) {
         self.status.remove(CPUFlags::CARRY)
     }
 
@@ -674,11 +968,13 @@ impl CPU {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cheats::Cheat;
+    use crate::joypad::JoypadButton;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0x05, 0x00]).unwrap();
         assert_eq!(cpu.register_a, 5);
         assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
         assert!(cpu.status.bits() & 0b1000_0000 == 0);
@@ -687,10 +983,10 @@ mod test {
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
         let mut cpu = CPU::new();
-        cpu.load(vec![0xaa, 0x00]);
+        cpu.load(vec![0xaa, 0x00]).unwrap();
         cpu.reset();
         cpu.register_a = 10;
-        cpu.run();
+        cpu.run().unwrap();
 
         assert_eq!(cpu.register_x, 10)
     }
@@ -698,7 +994,7 @@ mod test {
     #[test]
     fn test_5_ops_working_together() {
         let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]).unwrap();
 
         assert_eq!(cpu.register_x, 0xc1)
     }
@@ -706,10 +1002,10 @@ mod test {
     #[test]
     fn test_inx_overflow() {
         let mut cpu = CPU::new();
-        cpu.load(vec![0xe8, 0xe8, 0x00]);
+        cpu.load(vec![0xe8, 0xe8, 0x00]).unwrap();
         cpu.reset();
         cpu.register_x = 0xff;
-        cpu.run();
+        cpu.run().unwrap();
 
         assert_eq!(cpu.register_x, 1)
     }
@@ -719,8 +1015,251 @@ mod test {
         let mut cpu = CPU::new();
         cpu.mem_write(0x10, 0x55);
 
-        cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
+        cpu.load_and_run(vec![0xa5, 0x10, 0x00]).unwrap();
 
         assert_eq!(cpu.register_a, 0x55);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_opcode_table_indexed_by_code() {
+        for (code, op) in opcodes::OPCODE_TABLE.iter().enumerate() {
+            assert_eq!(op.code as usize, code);
+        }
+        for op in opcodes::CPU_OPS_CODES.iter() {
+            assert_eq!(opcodes::OPCODE_TABLE[op.code as usize].mnemonic, op.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_jsr_rts_round_trip() {
+        let mut cpu = CPU::new();
+        // JSR $0606; INX; BRK; (pad); LDX #$41; RTS
+        cpu.load_and_run(vec![0x20, 0x06, 0x06, 0xe8, 0x00, 0xea, 0xa2, 0x41, 0x60]).unwrap();
+
+        assert_eq!(cpu.register_x, 0x42)
+    }
+
+    #[test]
+    fn test_step_reports_opcode_and_cycles() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xa9, 0x05, 0xad, 0x00, 0x02, 0x00]).unwrap();
+        cpu.reset();
+
+        let step = cpu.step().unwrap();
+        assert_eq!(step.opcode.mnemonic, "LDA");
+        assert_eq!(step.cycles, 2);
+        assert_eq!(step.state, CpuState::Running);
+        assert_eq!(cpu.program_counter, 0x0602);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().state, CpuState::Halted);
+        assert_eq!(cpu.cycles, 2 + 4 + 7);
+    }
+
+    #[test]
+    fn test_jam_locks_cpu_until_reset() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0x02, 0xe8, 0x00]).unwrap();
+        cpu.reset();
+
+        let jammed = Err(CpuError::Jammed { pc: 0x0601, byte: 0x02 });
+        assert_eq!(cpu.run_for_cycles(100), jammed);
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.step().map(|step| step.state), jammed);
+        assert_eq!(cpu.register_x, 1);
+
+        cpu.reset();
+        assert_eq!(cpu.step().unwrap().state, CpuState::Running);
+    }
+
+    #[test]
+    fn test_run_for_cycles_and_run_until() {
+        let mut cpu = CPU::new();
+        // loop: INX; JMP loop
+        cpu.load(vec![0xe8, 0x4c, 0x00, 0x06]).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.run_for_cycles(50), Ok(CpuState::Running));
+        assert_eq!(cpu.cycles, 50);
+        assert_eq!(cpu.register_x, 10);
+
+        assert_eq!(cpu.run_until(|cpu| cpu.register_x == 0x20), Ok(CpuState::Running));
+        assert_eq!(cpu.register_x, 0x20);
+    }
+
+    #[test]
+    fn test_nmi_serviced_before_next_instruction() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0xe8, 0x00]).unwrap();
+        cpu.reset();
+        // Handler: LDA #$42; RTI
+        cpu.mem_write(0x0700, 0xa9);
+        cpu.mem_write(0x0701, 0x42);
+        cpu.mem_write(0x0702, 0x40);
+        cpu.mem_write_u16(0xFFFA, 0x0700);
+
+        cpu.step().unwrap();
+        cpu.trigger_nmi();
+        let step = cpu.step().unwrap();
+        assert!(step.interrupt_serviced);
+        assert_eq!(step.opcode.mnemonic, "LDA");
+        assert_eq!(step.cycles, 7 + 2);
+        assert!(cpu.status.contains(CPUFlags::INTERRUPT_DISABLE));
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_unknown_opcode_is_reported() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0xff, 0x00]).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.run(), Err(CpuError::UnknownOpcode { pc: 0x0601, byte: 0xff }));
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_program_too_large() {
+        let mut cpu = CPU::new();
+        let program = vec![0xea; 0x10000];
+
+        assert_eq!(cpu.load(program), Err(CpuError::ProgramTooLarge { len: 0x10000, max: 0xF9FC }));
+    }
+
+    #[test]
+    fn test_unsupported_addressing_mode() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xea, 0x00]).unwrap();
+        cpu.reset();
+        cpu.program_counter = 0x0601;
+
+        assert_eq!(
+            cpu.lda(&AddressingMode::NoneAddressing),
+            Err(CpuError::UnsupportedAddressing { pc: 0x0600, mode: AddressingMode::NoneAddressing })
+        );
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xa9, 0x42, 0x85, 0x10, 0xe8, 0x00]).unwrap();
+        cpu.reset();
+        cpu.step().unwrap();
+        let state = cpu.save_state();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.mem_read(0x10), 0);
+        assert_eq!(cpu.cycles, 2);
+
+        assert_eq!(cpu.load_state(&state[1..]), Err(CpuError::InvalidSaveState));
+    }
+
+    #[test]
+    fn test_joypad_read_through_4016() {
+        let mut cpu = CPU::new();
+        cpu.joypad1.set_buttons(JoypadButton::BUTTON_A | JoypadButton::START);
+        cpu.mem_write(0x4016, 1);
+        cpu.mem_write(0x4016, 0);
+
+        let bits: Vec<u8> = (0..9).map(|_| cpu.mem_read(0x4016)).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(cpu.mem_read(0x4017), 0);
+    }
+
+    #[test]
+    fn test_game_genie_patches_rom_reads() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x91D9, 0xCE);
+        cpu.mem_write(0x0300, 0xCE);
+        cpu.cheats.add(Cheat::parse("SXIOPO").unwrap());
+
+        assert_eq!(cpu.mem_read(0x91D9), 0xAD);
+        assert_eq!(cpu.mem_read(0x0300), 0xCE);
+
+        cpu.cheats.set_enabled(0, false);
+        assert_eq!(cpu.mem_read(0x91D9), 0xCE);
+    }
+
+    // Reports decode/dispatch throughput, run with
+    // `cargo test --release -- --ignored --nocapture test_dispatch_throughput`
+    #[test]
+    #[ignore]
+    fn test_dispatch_throughput() {
+        // LDY #0; loop_y: LDX #0; loop_x: INX; BNE loop_x; INY; BNE loop_y; BRK
+        let program = vec![0xa0, 0x00, 0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0xc8, 0xd0, 0xf8, 0x00];
+        let mut cpu = CPU::new();
+        cpu.load(program).unwrap();
+
+        let mut instructions: u64 = 0;
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            cpu.reset();
+            cpu.run_with_callback(|_| instructions += 1).unwrap();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        println!("{} instructions in {:.3}s: {:.0} instructions/s", instructions, elapsed, instructions as f64 / elapsed);
+    }
+
+    #[test]
+    fn test_load_nrom_and_save_ram() {
+        let mut raw = crate::cartridge::test::test_ines(1, 0b10);
+        raw[16] = 0xa9;
+        raw[16 + 0x3FFC] = 0x00;
+        raw[16 + 0x3FFD] = 0x80;
+        let rom = Rom::new(&raw).unwrap();
+
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.mem_read(0xC000), 0xa9);
+
+        cpu.load_save_ram(&[1, 2, 3]);
+        cpu.mem_write(0x7FFF, 4);
+        assert_eq!(&cpu.save_ram()[..3], &[1, 2, 3]);
+        assert_eq!(cpu.save_ram().len(), 0x2000);
+        assert_eq!(cpu.save_ram()[0x1FFF], 4);
+
+        let mapper1 = Rom { mapper: 1, ..rom };
+        assert_eq!(cpu.load_rom(&mapper1), Err(CpuError::UnsupportedMapper(1)));
+    }
+
+    #[test]
+    fn test_memory_observers() {
+        let accesses = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut cpu = CPU::new();
+        // LDA $10; STA $11; BRK
+        cpu.load(vec![0xa5, 0x10, 0x85, 0x11, 0x00]).unwrap();
+        cpu.reset();
+        cpu.mem_write(0x10, 0x42);
+
+        let log = accesses.clone();
+        let all = AccessKind::READ | AccessKind::WRITE | AccessKind::EXECUTE;
+        let id = cpu.observers.add(all, 0x0010..=0x0601, move |access| log.borrow_mut().push(*access));
+        cpu.run().unwrap();
+
+        let accesses = accesses.borrow();
+        let summary: Vec<_> = accesses.iter().map(|a| (a.kind, a.addr, a.value, a.pc)).collect();
+        assert_eq!(summary, vec![
+            (AccessKind::EXECUTE, 0x0600, 0xa5, 0x0600),
+            (AccessKind::READ, 0x0601, 0x10, 0x0600),
+            (AccessKind::READ, 0x0010, 0x42, 0x0600),
+            (AccessKind::WRITE, 0x0011, 0x42, 0x0602),
+        ]);
+        assert_eq!(accesses[3].cycle, 3);
+
+        assert!(cpu.observers.remove(id));
+        assert!(cpu.observers.is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::symbols::{self, Location};
+
+// Line types of ld65 debug info. C lines are preferred over the assembly the
+// compiler generated for them, macro expansions come last.
+const LINE_ASM: u64 = 0;
+const LINE_C: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceFile {
+    name: String,
+    // None when the file cannot be found next to the debug file
+    text: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SourceLine {
+    file: usize,
+    line: usize,
+    rank: u8,
+}
+
+// Source positions from a cc65/ca65 .dbg file: which file and line produced
+// each byte of the program, and the reverse for file:line breakpoints.
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    prg_len: usize,
+    files: HashMap<usize, SourceFile>,
+    lines: Vec<SourceLine>,
+    by_location: HashMap<Location, usize>,
+    // First byte of each span a line produced
+    by_line: HashMap<(usize, usize), Vec<Location>>,
+}
+
+impl DebugInfo {
+    // Source files are looked up as written in the debug file, then next to it
+    pub fn load<P: AsRef<Path>>(path: P, prg_len: usize) -> Result<DebugInfo, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        DebugInfo::parse(&text, prg_len, &base_dir).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn parse(text: &str, prg_len: usize, base_dir: &Path) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo { prg_len, ..DebugInfo::default() };
+        let mut segments: HashMap<u64, (u64, Option<u64>)> = HashMap::new();
+        let mut spans: HashMap<u64, (u64, u64, u64)> = HashMap::new();
+        let mut line_records = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let Some((kind, fields)) = symbols::parse_dbg_line(line) else {
+                continue;
+            };
+            let number_field = |key: &str| fields.get(key).copied().and_then(symbols::parse_number);
+            let missing = |what: &str| format!("line {}: {} record without {}", number + 1, kind, what);
+            match kind {
+                "file" => {
+                    let id = number_field("id").ok_or_else(|| missing("id"))?;
+                    let name = fields.get("name").ok_or_else(|| missing("name"))?.to_string();
+                    let text = read_source(&name, base_dir);
+                    info.files.insert(id as usize, SourceFile { name, text });
+                }
+                "seg" => {
+                    let id = number_field("id").ok_or_else(|| missing("id"))?;
+                    segments.insert(id, (number_field("start").unwrap_or(0), number_field("ooffs")));
+                }
+                "span" => {
+                    let id = number_field("id").ok_or_else(|| missing("id"))?;
+                    let segment = number_field("seg").ok_or_else(|| missing("seg"))?;
+                    let start = number_field("start").ok_or_else(|| missing("start"))?;
+                    spans.insert(id, (segment, start, number_field("size").unwrap_or(0)));
+                }
+                "line" => {
+                    // Lines without code, comments and the like, have no spans
+                    let Some(span_ids) = fields.get("span") else {
+                        continue;
+                    };
+                    let file = number_field("file").ok_or_else(|| missing("file"))?;
+                    let line = number_field("line").ok_or_else(|| missing("line"))?;
+                    let rank = match number_field("type").unwrap_or(LINE_ASM) {
+                        LINE_C => 0,
+                        LINE_ASM => 1,
+                        _ => 2,
+                    };
+                    let span_ids: Vec<u64> = span_ids.split('+').filter_map(symbols::parse_number).collect();
+                    line_records.push((SourceLine { file: file as usize, line: line as usize, rank }, span_ids));
+                }
+                _ => (),
+            }
+        }
+
+        for (source_line, span_ids) in line_records {
+            let index = info.lines.len();
+            info.lines.push(source_line);
+            for span_id in span_ids {
+                let Some(&(segment, start, size)) = spans.get(&span_id) else {
+                    continue;
+                };
+                let Some(&(seg_start, ooffs)) = segments.get(&segment) else {
+                    continue;
+                };
+                for offset in start..start + size {
+                    let location = info.normalize(symbols::segment_location(seg_start, ooffs, offset));
+                    let better = match info.by_location.get(&location) {
+                        Some(&existing) => source_line.rank < info.lines[existing].rank,
+                        None => true,
+                    };
+                    if better {
+                        info.by_location.insert(location, index);
+                    }
+                    if offset == start {
+                        info.by_line.entry((source_line.file, source_line.line)).or_default().push(location);
+                    }
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    fn normalize(&self, location: Location) -> Location {
+        match location {
+            Location::Cpu(addr) => symbols::map_address(self.prg_len, addr),
+            prg => prg,
+        }
+    }
+
+    fn line_at(&self, addr: u16) -> Option<&SourceLine> {
+        let index = self.by_location.get(&symbols::map_address(self.prg_len, addr))?;
+        Some(&self.lines[*index])
+    }
+
+    // `main.c:42` for the line that produced the code at `addr`
+    pub fn position(&self, addr: u16) -> Option<String> {
+        let line = self.line_at(addr)?;
+        let file = self.files.get(&line.file)?;
+        Some(format!("{}:{}", file.name, line.line))
+    }
+
+    // Identifies the source line at `addr`, for stepping until it changes
+    pub fn line_id(&self, addr: u16) -> Option<(usize, usize)> {
+        self.line_at(addr).map(|line| (line.file, line.line))
+    }
+
+    // Code locations of a line. `file` may leave out leading directories.
+    pub fn locations(&self, file: &str, line: usize) -> Vec<Location> {
+        let mut locations: Vec<Location> = self
+            .files
+            .iter()
+            .filter(|(_, source)| source.name == file || Path::new(&source.name).ends_with(file))
+            .filter_map(|(id, _)| self.by_line.get(&(*id, line)))
+            .flatten()
+            .copied()
+            .collect();
+        locations.sort();
+        locations.dedup();
+        locations
+    }
+
+    // Source around the line that produced the code at `addr`, marking that line
+    pub fn context(&self, addr: u16, radius: usize) -> Option<String> {
+        let line = self.line_at(addr)?;
+        let file = self.files.get(&line.file)?;
+        let Some(text) = &file.text else {
+            return Some(format!("{}:{} (source not found)", file.name, line.line));
+        };
+
+        let first = line.line.saturating_sub(radius).max(1);
+        let last = (line.line + radius).min(text.len());
+        let mut out = vec![format!("{}:", file.name)];
+        for number in first..=last {
+            let marker = if number == line.line { ">" } else { " " };
+            out.push(format!("{} {:>5}  {}", marker, number, text[number - 1]));
+        }
+        Some(out.join("\n"))
+    }
+}
+
+fn read_source(name: &str, base_dir: &Path) -> Option<Vec<String>> {
+    let candidates = [PathBuf::from(name), base_dir.join(name)];
+    candidates
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|text| text.lines().map(str::to_string).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    pub const TEST_DBG: &str = concat!(
+        "version major=2,minor=0\n",
+        "file id=0,name=\"src/main.c\",size=100,mtime=0x5B2C0000,mod=0\n",
+        "file id=1,name=\"main.s\",size=100,mtime=0x5B2C0000,mod=0\n",
+        "seg id=0,name=\"CODE\",start=0x00C000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16\n",
+        "span id=0,seg=0,start=0,size=2\n",
+        "span id=1,seg=0,start=2,size=3\n",
+        "span id=2,seg=0,start=5,size=1\n",
+        "line id=0,file=1,line=10,span=0+1\n",
+        "line id=1,file=0,line=3,type=1,span=1\n",
+        "line id=2,file=1,line=11,span=2\n",
+        "line id=3,file=1,line=1\n",
+    );
+
+    #[test]
+    fn test_positions_and_line_locations() {
+        let info = DebugInfo::parse(TEST_DBG, 0x4000, Path::new("")).unwrap();
+        assert_eq!(info.position(0xC000).as_deref(), Some("main.s:10"));
+        // The C line wins over the assembly generated for it, mirrors map to the same bytes
+        assert_eq!(info.position(0xC003).as_deref(), Some("src/main.c:3"));
+        assert_eq!(info.position(0x8003).as_deref(), Some("src/main.c:3"));
+        assert_eq!(info.position(0xC006), None);
+        assert_eq!(info.line_id(0xC005), Some((1, 11)));
+
+        assert_eq!(info.locations("main.c", 3), vec![Location::Prg(2)]);
+        assert_eq!(info.locations("main.s", 10), vec![Location::Prg(0), Location::Prg(2)]);
+        assert!(info.locations("other.c", 3).is_empty());
+        assert_eq!(info.context(0xC000, 2).as_deref(), Some("main.s:10 (source not found)"));
+
+        let mut with_source = info.clone();
+        with_source.files.get_mut(&1).unwrap().text = Some((1..=12).map(|n| format!("line {}", n)).collect());
+        assert_eq!(
+            with_source.context(0xC005, 1).unwrap(),
+            "main.s:\n     10  line 10\n>    11  line 11\n     12  line 12"
+        );
+    }
+}
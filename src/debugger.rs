@@ -0,0 +1,514 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cheats::Cheat;
+use crate::cpu::{CpuState, CPU};
+use crate::dbginfo::DebugInfo;
+use crate::disasm;
+use crate::ramsearch::{Comparison, Operand, RamSearch, ValueType};
+use crate::symbols::{Location, SymbolTable};
+
+const INTERNAL_RAM_END: u16 = 0x07FF;
+const SEARCH_LIST_LIMIT: usize = 32;
+const DISASM_LINES: usize = 10;
+// Description of the raw codes freeze adds, so unfreeze leaves other cheats alone
+const FROZEN: &str = "frozen";
+const LIST_RADIUS: usize = 5;
+// Gives up on a source line that never ends, an infinite loop on one line
+const LINE_STEP_LIMIT: usize = 1_000_000;
+
+const HELP: &str = "\
+regs                            show the CPU registers
+search new [start-end] [type]   snapshot a range (default 0000-07FF u8), type is u8 i8 u16 i16
+search <op> [value]             keep candidates where current <op> value, or the previous snapshot
+                                when no value is given; op is == != > < >= <=
+search list                     show the remaining candidates
+freeze [addr value]             pin a byte (or a word when value > $FF), no arguments lists them
+unfreeze <addr>                 release a frozen address
+break [addr|file:line]          stop before the instruction at addr, no arguments lists breakpoints
+delete <addr|file:line>         remove a breakpoint
+pause / continue                stop and resume emulation
+step [count]                    execute instructions one by one, tracing each
+stepline [count]                execute until the source line changes, needs a .dbg file
+disasm [addr] [count]           disassemble from addr, the PC by default
+list [addr]                     show the source around addr, the PC by default
+symbols <file>                  load labels from a .dbg, .nl, .mlb or addr=name file, .dbg adds source lines
+addresses are hex or labels, values are decimal unless prefixed with $ or 0x";
+
+// Text command interface shared by every frontend.
+// Commands return their output, or a message describing what went wrong.
+#[derive(Default)]
+pub struct Debugger {
+    search: Option<RamSearch>,
+    symbols: SymbolTable,
+    debug_info: Option<DebugInfo>,
+    breakpoints: BTreeSet<Location>,
+    // Start address of every freeze made here, with its width in bytes and value
+    freezes: BTreeMap<u16, (u16, u16)>,
+    paused: bool,
+    // Breakpoint just resumed from, not hit again until execution moves on
+    resume_pc: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    pub fn search(&self) -> Option<&RamSearch> {
+        self.search.as_ref()
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    // Frontends skip emulation while paused and keep running commands
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // Resuming does not stop again on a breakpoint at the current instruction
+    pub fn set_paused(&mut self, paused: bool, cpu: &CPU) {
+        self.paused = paused;
+        self.resume_pc = if paused { None } else { Some(cpu.program_counter) };
+    }
+
+    pub fn has_breakpoints(&self) -> bool {
+        !self.breakpoints.is_empty()
+    }
+
+    // Call before every instruction, returns true when a breakpoint pauses emulation
+    pub fn check_breakpoint(&mut self, cpu: &CPU) -> bool {
+        let pc = cpu.program_counter;
+        if self.resume_pc.take() == Some(pc) || !self.breakpoints.contains(&self.symbols.location(pc)) {
+            return false;
+        }
+        self.paused = true;
+        true
+    }
+
+    // Address with its label and source line, for messages
+    pub fn describe(&self, addr: u16) -> String {
+        let mut out = match self.symbols.label(addr) {
+            Some(label) => format!("${:04X} {}", addr, label),
+            None => format!("${:04X}", addr),
+        };
+        if let Some(position) = self.debug_info.as_ref().and_then(|info| info.position(addr)) {
+            out += &format!(" ({})", position);
+        }
+        out
+    }
+
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] => Ok(HELP.to_string()),
+            ["regs"] => Ok(format!(
+                "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                cpu.program_counter, cpu.register_a, cpu.register_x, cpu.register_y,
+                cpu.status.bits(), cpu.stack_pointer, cpu.cycles,
+            )),
+            ["search", rest @ ..] => self.search_command(cpu, rest),
+            ["freeze"] => Ok(self.list_freezes()),
+            ["freeze", addr, value] => self.freeze(cpu, parse_address(addr)?, parse_value(value)?),
+            ["break"] => Ok(self.list_breakpoints()),
+            ["break", target] => {
+                let locations = self.parse_breakpoint(target)?;
+                self.breakpoints.extend(locations.iter().copied());
+                Ok(self.describe_locations(&locations, ""))
+            }
+            ["delete", target] => {
+                let locations: Vec<Location> = self
+                    .parse_breakpoint(target)?
+                    .into_iter()
+                    .filter(|location| self.breakpoints.remove(location))
+                    .collect();
+                match locations.is_empty() {
+                    false => Ok(self.describe_locations(&locations, " removed")),
+                    true => Err(format!("no breakpoint at {}", target)),
+                }
+            }
+            ["pause"] => {
+                self.set_paused(true, cpu);
+                Ok(disasm::trace_line(cpu, &self.symbols))
+            }
+            ["continue"] => {
+                self.set_paused(false, cpu);
+                Ok(String::new())
+            }
+            ["step"] => self.step(cpu, 1),
+            ["step", count] => self.step(cpu, count.parse().map_err(|_| format!("invalid count {:?}", count))?),
+            ["stepline"] => self.step_lines(cpu, 1),
+            ["stepline", count] => self.step_lines(cpu, count.parse().map_err(|_| format!("invalid count {:?}", count))?),
+            ["list"] => self.list(cpu.program_counter),
+            ["list", target] => self.list(self.parse_cpu_address(target)?),
+            ["disasm"] => Ok(self.disassemble(cpu, cpu.program_counter, DISASM_LINES)),
+            ["disasm", target] => Ok(self.disassemble(cpu, self.parse_cpu_address(target)?, DISASM_LINES)),
+            ["disasm", target, count] => {
+                let count = count.parse().map_err(|_| format!("invalid count {:?}", count))?;
+                Ok(self.disassemble(cpu, self.parse_cpu_address(target)?, count))
+            }
+            ["symbols", path] => {
+                let count = self.symbols.load(path)?;
+                if !path.ends_with(".dbg") {
+                    return Ok(format!("{} labels loaded", count));
+                }
+                self.debug_info = Some(DebugInfo::load(path, self.symbols.prg_len())?);
+                Ok(format!("{} labels and source lines loaded", count))
+            }
+            ["unfreeze", addr] => {
+                let addr = parse_address(addr)?;
+                match self.unfreeze(cpu, addr) {
+                    true => Ok(format!("${:04X} released", addr)),
+                    false => Err(format!("${:04X} is not frozen", addr)),
+                }
+            }
+            _ => Err(format!("unknown command {:?}, try help", line.trim())),
+        }
+    }
+
+    fn freeze(&mut self, cpu: &mut CPU, addr: u16, value: i32) -> Result<String, String> {
+        if !(-0x8000..=0xFFFF).contains(&value) {
+            return Err(format!("{} does not fit in 16 bits", value));
+        }
+        // Anything that does not fit a signed or unsigned byte pins a little endian word
+        let width = if (-0x80..=0xFF).contains(&value) { 1_u16 } else { 2 };
+
+        // Freezes sharing a byte with the new one are replaced whole
+        let bytes = |start: u16, width: u16| (0..width).map(move |i| start.wrapping_add(i));
+        let overlapping: Vec<u16> = self
+            .freezes
+            .iter()
+            .filter(|&(&start, &(other, _))| bytes(start, other).any(|byte| bytes(addr, width).any(|new| new == byte)))
+            .map(|(&start, _)| start)
+            .collect();
+        for start in overlapping {
+            self.unfreeze(cpu, start);
+        }
+
+        for (i, target) in bytes(addr, width).enumerate() {
+            let byte = (value >> (8 * i)) as u8;
+            let mut cheat = Cheat::parse(&format!("{:04X}:{:02X}", target, byte)).map_err(|err| err.to_string())?;
+            cheat.description = FROZEN.to_string();
+            cpu.cheats.add(cheat);
+        }
+        self.freezes.insert(addr, (width, value as u16));
+        Ok(format!("${:04X} frozen", addr))
+    }
+
+    // Only freezes made here, codes loaded from a cheat file are left out
+    fn list_freezes(&self) -> String {
+        if self.freezes.is_empty() {
+            return "nothing frozen".to_string();
+        }
+        let frozen: Vec<String> = self
+            .freezes
+            .iter()
+            .map(|(addr, &(width, value))| match width {
+                1 => format!("${:04X} = ${:02X}", addr, value as u8),
+                _ => format!("${:04X} = ${:04X}", addr, value),
+            })
+            .collect();
+        frozen.join("\n")
+    }
+
+    // Removes only the codes the matching freeze added
+    fn unfreeze(&mut self, cpu: &mut CPU, addr: u16) -> bool {
+        let Some((width, _)) = self.freezes.remove(&addr) else {
+            return false;
+        };
+        for i in 0..width {
+            let target = addr.wrapping_add(i);
+            let index = cpu.cheats.iter().position(|cheat| cheat.address == target && cheat.description == FROZEN);
+            if let Some(index) = index {
+                cpu.cheats.remove(index);
+            }
+        }
+        true
+    }
+
+    fn search_command(&mut self, cpu: &CPU, args: &[&str]) -> Result<String, String> {
+        if let ["new", rest @ ..] = args {
+            let mut range = 0x0000..=INTERNAL_RAM_END;
+            let mut value_type = ValueType::U8;
+            for arg in rest {
+                match parse_value_type(arg) {
+                    Some(parsed) => value_type = parsed,
+                    None => range = parse_range(arg)?,
+                }
+            }
+            let search = RamSearch::new(cpu, range, value_type);
+            let count = search.len();
+            self.search = Some(search);
+            return Ok(format!("{} candidates", count));
+        }
+
+        let search = self.search.as_mut().ok_or("no search running, start one with search new")?;
+        match args {
+            ["list"] => {
+                let mut out = format!("{} candidates", search.len());
+                for (addr, value) in search.candidates().take(SEARCH_LIST_LIMIT) {
+                    out += &format!("\n${:04X}: {}", addr, value);
+                }
+                if search.len() > SEARCH_LIST_LIMIT {
+                    out += "\n...";
+                }
+                Ok(out)
+            }
+            [op] => {
+                let count = search.filter(cpu, parse_comparison(op)?, Operand::Previous);
+                Ok(format!("{} candidates", count))
+            }
+            [op, value] => {
+                let count = search.filter(cpu, parse_comparison(op)?, Operand::Value(parse_value(value)?));
+                Ok(format!("{} candidates", count))
+            }
+            _ => Err("usage: search new [start-end] [type] | search <op> [value] | search list".to_string()),
+        }
+    }
+
+    fn parse_location(&self, arg: &str) -> Result<Location, String> {
+        match self.symbols.resolve(arg) {
+            Some(location) => Ok(location),
+            None => parse_address(arg)
+                .map(|addr| self.symbols.location(addr))
+                .map_err(|_| format!("{:?} is neither an address nor a known label", arg)),
+        }
+    }
+
+    // `file:line` stops at every piece of code the line produced
+    fn parse_breakpoint(&self, arg: &str) -> Result<Vec<Location>, String> {
+        let Some((file, line)) = arg.rsplit_once(':') else {
+            return self.parse_location(arg).map(|location| vec![location]);
+        };
+        let info = self.debug_info.as_ref().ok_or("no source lines, load a .dbg file with symbols")?;
+        let line = line.parse().map_err(|_| format!("invalid line {:?}", line))?;
+        match info.locations(file, line) {
+            locations if locations.is_empty() => Err(format!("no code for {}", arg)),
+            locations => Ok(locations),
+        }
+    }
+
+    fn describe_locations(&self, locations: &[Location], suffix: &str) -> String {
+        let lines: Vec<String> = locations
+            .iter()
+            .map(|&location| format!("breakpoint at {}{}", self.describe(self.symbols.address(location)), suffix))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn parse_cpu_address(&self, arg: &str) -> Result<u16, String> {
+        self.parse_location(arg).map(|location| self.symbols.address(location))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let lines: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|&location| self.describe(self.symbols.address(location)))
+            .collect();
+        lines.join("\n")
+    }
+
+    // Steps pause emulation, the frontend would otherwise run on from there
+    fn step(&mut self, cpu: &mut CPU, count: usize) -> Result<String, String> {
+        self.paused = true;
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            lines.push(disasm::trace_line(cpu, &self.symbols));
+            match cpu.step() {
+                Ok(step) if step.state == CpuState::Running => (),
+                Ok(_) => {
+                    lines.push("CPU halted".to_string());
+                    break;
+                }
+                Err(err) => {
+                    lines.push(err.to_string());
+                    break;
+                }
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // Runs whole source lines, stepping into calls, then shows where it stopped
+    fn step_lines(&mut self, cpu: &mut CPU, count: usize) -> Result<String, String> {
+        let info = self.debug_info.as_ref().ok_or("no source lines, load a .dbg file with symbols")?;
+        self.paused = true;
+        for _ in 0..count {
+            let line = info.line_id(cpu.program_counter);
+            let mut steps = 0;
+            loop {
+                match cpu.step() {
+                    Ok(step) if step.state == CpuState::Running => (),
+                    Ok(_) => return Ok(format!("CPU halted at {}", self.describe(cpu.program_counter))),
+                    Err(err) => return Err(err.to_string()),
+                }
+                steps += 1;
+                // Code without line info, library routines say, is stepped through
+                match info.line_id(cpu.program_counter) {
+                    Some(next) if Some(next) != line => break,
+                    _ if steps >= LINE_STEP_LIMIT => {
+                        return Err(format!("line did not end after {} instructions", LINE_STEP_LIMIT))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.list(cpu.program_counter)
+    }
+
+    fn list(&self, addr: u16) -> Result<String, String> {
+        let info = self.debug_info.as_ref().ok_or("no source lines, load a .dbg file with symbols")?;
+        info.context(addr, LIST_RADIUS).ok_or_else(|| format!("no source line for {}", self.describe(addr)))
+    }
+
+    fn disassemble(&self, cpu: &CPU, mut addr: u16, count: usize) -> String {
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            if let Some(label) = self.symbols.label(addr) {
+                lines.push(format!("{}:", label));
+            }
+            let instruction = disasm::disassemble(cpu, addr, &self.symbols);
+            let marker = if addr == cpu.program_counter { ">" } else { " " };
+            lines.push(format!("{} {:04X}  {:<8}  {}", marker, addr, instruction.hex_bytes(), instruction.text));
+            addr = instruction.next_addr();
+        }
+        lines.join("\n")
+    }
+}
+
+
+
+fn parse_address(arg: &str) -> Result<u16, String> {
+    u16::from_str_radix(arg.trim_start_matches('$'), 16).map_err(|_| format!("invalid address {:?}", arg))
+}
+
+fn parse_value(arg: &str) -> Result<i32, String> {
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, arg),
+    };
+    let parsed = match digits.strip_prefix('$').or_else(|| digits.strip_prefix("0x")) {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    let value = parsed.map_err(|_| format!("invalid value {:?}", arg))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn parse_range(arg: &str) -> Result<std::ops::RangeInclusive<u16>, String> {
+    let (start, end) = arg.split_once('-').ok_or_else(|| format!("invalid range {:?}, expected start-end", arg))?;
+    let (start, end) = (parse_address(start)?, parse_address(end)?);
+    if start > end {
+        return Err(format!("invalid range {:?}", arg));
+    }
+    Ok(start..=end)
+}
+
+fn parse_value_type(arg: &str) -> Option<ValueType> {
+    match arg {
+        "u8" => Some(ValueType::U8),
+        "i8" => Some(ValueType::I8),
+        "u16" => Some(ValueType::U16),
+        "i16" => Some(ValueType::I16),
+        _ => None,
+    }
+}
+
+fn parse_comparison(arg: &str) -> Result<Comparison, String> {
+    match arg {
+        "==" | "=" => Ok(Comparison::Equal),
+        "!=" => Ok(Comparison::NotEqual),
+        ">" => Ok(Comparison::Greater),
+        "<" => Ok(Comparison::Less),
+        ">=" => Ok(Comparison::GreaterOrEqual),
+        "<=" => Ok(Comparison::LessOrEqual),
+        _ => Err(format!("invalid comparison {:?}", arg)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::MEM;
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut cpu = CPU::new();
+        // $0600: INX; INX; $0602: INY; BRK
+        cpu.load(vec![0xe8, 0xe8, 0xc8, 0x00]).unwrap();
+        cpu.reset();
+        let mut symbols = SymbolTable::new(0);
+        symbols.parse_simple("0602=second").unwrap();
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols);
+
+        assert_eq!(debugger.execute(&mut cpu, "break second").unwrap(), "breakpoint at $0602 second");
+        assert!(debugger.execute(&mut cpu, "break nowhere").is_err());
+        cpu.run_for_cycles_while(100, |cpu| !debugger.check_breakpoint(cpu)).unwrap();
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert!(debugger.paused());
+
+        let disasm = debugger.execute(&mut cpu, "disasm 0600 3").unwrap();
+        assert_eq!(disasm, "  0600  E8        INX\n  0601  E8        INX\nsecond:\n> 0602  C8        INY");
+
+        let trace = debugger.execute(&mut cpu, "step").unwrap();
+        assert!(trace.starts_with("0602  C8        second: INY"), "{}", trace);
+        assert_eq!(cpu.register_y, 1);
+
+        debugger.execute(&mut cpu, "delete 602").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "break").unwrap(), "no breakpoints");
+        debugger.execute(&mut cpu, "continue").unwrap();
+        assert!(!debugger.paused());
+    }
+
+    #[test]
+    fn test_search_and_freeze_commands() {
+        let mut cpu = CPU::new();
+        let mut debugger = Debugger::new();
+        cpu.mem_write(0x0042, 5);
+
+        assert_eq!(debugger.execute(&mut cpu, "search new 0000-00FF").unwrap(), "256 candidates");
+        cpu.mem_write(0x0042, 4);
+        assert_eq!(debugger.execute(&mut cpu, "search <").unwrap(), "1 candidates");
+        assert_eq!(debugger.execute(&mut cpu, "search == $04").unwrap(), "1 candidates");
+        assert!(debugger.execute(&mut cpu, "search list").unwrap().contains("$0042: 4"));
+
+        debugger.execute(&mut cpu, "freeze 42 9").unwrap();
+        cpu.mem_write(0x0042, 0);
+        assert_eq!(cpu.mem_read(0x0042), 9);
+        assert_eq!(debugger.execute(&mut cpu, "freeze").unwrap(), "$0042 = $09");
+
+        // Releasing one byte leaves the next freeze and other cheats at the address alone
+        debugger.execute(&mut cpu, "freeze 43 7").unwrap();
+        cpu.cheats.add(Cheat::parse("0042:01:00").unwrap());
+        debugger.execute(&mut cpu, "unfreeze $42").unwrap();
+        assert_eq!(cpu.mem_read(0x0042), 1);
+        assert_eq!(cpu.mem_read(0x0043), 7);
+        assert!(debugger.execute(&mut cpu, "unfreeze 42").is_err());
+
+        // A word freeze is released as a whole
+        debugger.execute(&mut cpu, "freeze 10 $1234").unwrap();
+        assert_eq!(debugger.execute(&mut cpu, "freeze").unwrap(), "$0010 = $1234\n$0043 = $07");
+        debugger.execute(&mut cpu, "unfreeze 10").unwrap();
+        assert_eq!(cpu.cheats.iter().filter(|cheat| cheat.description == FROZEN).count(), 1);
+        assert!(debugger.execute(&mut cpu, "search ~ 1").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::cpu::{AddressingMode, CPU};
+use crate::opcodes;
+use crate::symbols::SymbolTable;
+
+const JMP_INDIRECT: u8 = 0x6C;
+const BRANCHES: [&str; 8] = ["BPL", "BMI", "BVC", "BVS", "BCC", "BCS", "BNE", "BEQ"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // Assembly with operands named through the symbol table
+    pub text: String,
+}
+
+impl Instruction {
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+
+    pub fn hex_bytes(&self) -> String {
+        self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+fn name(symbols: &SymbolTable, addr: u16, zero_page: bool) -> String {
+    match symbols.label(addr) {
+        Some(label) => label.to_string(),
+        None if zero_page => format!("${:02X}", addr),
+        None => format!("${:04X}", addr),
+    }
+}
+
+pub fn disassemble(cpu: &CPU, addr: u16, symbols: &SymbolTable) -> Instruction {
+    let opcode = &opcodes::OPCODE_TABLE[cpu.peek(addr) as usize];
+    let bytes: Vec<u8> = (0..opcode.len as u16).map(|i| cpu.peek(addr.wrapping_add(i))).collect();
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+
+    let operand = match (opcode.mode, opcode.len) {
+        (AddressingMode::Immediate, _) => format!("#${:02X}", byte),
+        (AddressingMode::ZeroPage, _) => name(symbols, byte as u16, true),
+        (AddressingMode::ZeroPage_X, _) => format!("{},X", name(symbols, byte as u16, true)),
+        (AddressingMode::ZeroPage_Y, _) => format!("{},Y", name(symbols, byte as u16, true)),
+        (AddressingMode::Absolute, _) => name(symbols, word, false),
+        (AddressingMode::Absolute_X, _) => format!("{},X", name(symbols, word, false)),
+        (AddressingMode::Absolute_Y, _) => format!("{},Y", name(symbols, word, false)),
+        (AddressingMode::Indirect_X, _) => format!("({},X)", name(symbols, byte as u16, true)),
+        (AddressingMode::Indirect_Y, _) => format!("({}),Y", name(symbols, byte as u16, true)),
+        (AddressingMode::NoneAddressing, 1) => String::new(),
+        (AddressingMode::NoneAddressing, 2) if BRANCHES.contains(&opcode.mnemonic) => {
+            let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            name(symbols, target, false)
+        }
+        (AddressingMode::NoneAddressing, 2) => format!("${:02X}", byte),
+        (AddressingMode::NoneAddressing, _) if opcode.code == JMP_INDIRECT => format!("({})", name(symbols, word, false)),
+        (AddressingMode::NoneAddressing, _) => name(symbols, word, false),
+    };
+
+    let text = if operand.is_empty() {
+        opcode.mnemonic.to_string()
+    } else {
+        format!("{} {}", opcode.mnemonic, operand)
+    };
+    Instruction { addr, bytes, text }
+}
+
+// One line per instruction about to execute, in the style of the nestest log
+// with the label of the address in front of the instruction
+pub fn trace_line(cpu: &CPU, symbols: &SymbolTable) -> String {
+    let instruction = disassemble(cpu, cpu.program_counter, symbols);
+    let text = match symbols.label(cpu.program_counter) {
+        Some(label) => format!("{}: {}", label, instruction.text),
+        None => instruction.text.clone(),
+    };
+    format!(
+        "{:04X}  {:<8}  {:<32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        cpu.program_counter, instruction.hex_bytes(), text,
+        cpu.register_a, cpu.register_x, cpu.register_y, cpu.status.bits(), cpu.stack_pointer, cpu.cycles,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::symbols::Location;
+
+    #[test]
+    fn test_disassemble_with_labels() {
+        let mut cpu = CPU::new();
+        // LDA #$05; STA $10; BNE -4; JMP ($0200); LDA ($20),Y
+        cpu.load(vec![0xa9, 0x05, 0x85, 0x10, 0xd0, 0xfa, 0x6c, 0x00, 0x02, 0xb1, 0x20]).unwrap();
+        cpu.reset();
+        let mut symbols = SymbolTable::new(0);
+        symbols.insert(Location::Cpu(0x0010), "counter");
+        symbols.insert(Location::Cpu(0x0600), "main");
+
+        let texts: Vec<String> = [0x0600, 0x0602, 0x0604, 0x0606, 0x0609]
+            .iter()
+            .map(|&addr| disassemble(&cpu, addr, &symbols).text)
+            .collect();
+        assert_eq!(texts, ["LDA #$05", "STA counter", "BNE main", "JMP ($0200)", "LDA ($20),Y"]);
+        assert_eq!(disassemble(&cpu, 0x0606, &symbols).next_addr(), 0x0609);
+
+        let line = trace_line(&cpu, &symbols);
+        assert!(line.starts_with("0600  A9 05     main: LDA #$05 "), "{}", line);
+        assert!(line.ends_with("A:00 X:00 Y:00 P:24 SP:FD CYC:0"));
+    }
+}
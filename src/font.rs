@@ -0,0 +1,112 @@
+use crate::palette::Rgb;
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_ADVANCE: usize = GLYPH_HEIGHT + 1;
+
+// 3x5 glyphs, one row per byte with the leftmost pixel in bit 2.
+// Lowercase letters are drawn as uppercase.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ' ' => [0; GLYPH_HEIGHT],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+// Draws text onto an RGB24 image, clipping whatever falls outside it.
+// '\n' starts a new line below `x`.
+pub fn draw_text(rgb: &mut [u8], width: usize, x: i32, y: i32, text: &str, color: Rgb) {
+    let height = rgb.len() / 3 / width.max(1);
+    let (mut pen_x, mut pen_y) = (x, y);
+
+    for c in text.chars() {
+        if c == '\n' {
+            pen_x = x;
+            pen_y += LINE_ADVANCE as i32;
+            continue;
+        }
+
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                let (px, py) = (pen_x + col as i32, pen_y + row as i32);
+                if px < 0 || py < 0 || px as usize >= width || py as usize >= height {
+                    continue;
+                }
+                let offset = (py as usize * width + px as usize) * 3;
+                rgb[offset..offset + 3].copy_from_slice(&[color.0, color.1, color.2]);
+            }
+        }
+        pen_x += GLYPH_ADVANCE as i32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draw_clipped_text() {
+        let mut rgb = vec![0; 6 * 6 * 3];
+        draw_text(&mut rgb, 6, 0, 0, "1\nA", (255, 0, 0));
+        // Middle column of the '1' stem
+        assert_eq!(&rgb[(6 + 1) * 3..(6 + 1) * 3 + 3], &[255, 0, 0]);
+        assert_eq!(&rgb[0..3], &[0, 0, 0]);
+
+        // Fully outside the image, nothing drawn and no panic
+        let before = rgb.clone();
+        draw_text(&mut rgb, 6, -10, 20, "HUD", (255, 255, 255));
+        assert_eq!(rgb, before);
+    }
+}
@@ -0,0 +1,100 @@
+use std::cell::Cell;
+
+use crate::joypad::Joypad;
+
+// Reported after both controllers of a port, read first to last into the
+// bits of a byte from the most significant one
+const SIGNATURES: [u8; 2] = [0x10, 0x20];
+const REPORT_LEN: u8 = 24;
+
+// How four controllers share the two ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Multitap {
+    // Joypads 3 and 4 are not connected
+    #[default]
+    Off,
+    // NES Four Score: each port reports two controllers and a signature in turn
+    FourScore,
+    // Famicom expansion port adapters: joypads 3 and 4 answer on bit 1
+    Famicom,
+}
+
+impl Multitap {
+    pub fn from_name(name: &str) -> Option<Multitap> {
+        match name {
+            "off" => Some(Multitap::Off),
+            "fourscore" => Some(Multitap::FourScore),
+            "famicom" => Some(Multitap::Famicom),
+            _ => None,
+        }
+    }
+}
+
+// Shift registers of the Four Score, $4016 is port 0 and $4017 port 1
+#[derive(Debug, Default)]
+pub struct FourScore {
+    strobe: bool,
+    reads: [Cell<u8>; 2],
+}
+
+impl FourScore {
+    pub fn new() -> Self {
+        FourScore::default()
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.reads.iter().for_each(|reads| reads.set(0));
+        }
+    }
+
+    // `first` is joypad 1 or 2, `second` joypad 3 or 4. Reads past the report return 1.
+    pub fn read(&self, port: usize, first: &Joypad, second: &Joypad) -> u8 {
+        let index = self.reads[port].get();
+        let bit = match index {
+            0..=7 => first.buttons().bits() >> index,
+            8..=15 => second.buttons().bits() >> (index - 8),
+            16..=23 => SIGNATURES[port] >> (REPORT_LEN - 1 - index),
+            _ => 1,
+        };
+        if !self.strobe && index < REPORT_LEN {
+            self.reads[port].set(index + 1);
+        }
+        bit & 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{CPU, MEM};
+    use crate::joypad::JoypadButton;
+
+    fn read_report(cpu: &mut CPU, addr: u16) -> Vec<u8> {
+        cpu.mem_write(0x4016, 1);
+        cpu.mem_write(0x4016, 0);
+        (0..REPORT_LEN + 1).map(|_| cpu.mem_read(addr) & 0b11).collect()
+    }
+
+    #[test]
+    fn test_four_score_and_famicom_reports() {
+        let mut cpu = CPU::new();
+        cpu.multitap = Multitap::FourScore;
+        cpu.joypad1.set_buttons(JoypadButton::BUTTON_A);
+        cpu.joypad2.set_buttons(JoypadButton::RIGHT);
+        cpu.joypad3.set_buttons(JoypadButton::BUTTON_B);
+        cpu.joypad4.set_buttons(JoypadButton::START);
+
+        let port0 = read_report(&mut cpu, 0x4016);
+        assert_eq!(port0[..16], [1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(port0[16..], [0, 0, 0, 1, 0, 0, 0, 0, 1]);
+        let port1 = read_report(&mut cpu, 0x4017);
+        assert_eq!(port1[..16], [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(port1[16..], [0, 0, 1, 0, 0, 0, 0, 0, 1]);
+
+        cpu.multitap = Multitap::Famicom;
+        let port0 = read_report(&mut cpu, 0x4016);
+        assert_eq!(port0[..3], [0b01, 0b10, 0]);
+    }
+}
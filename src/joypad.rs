@@ -0,0 +1,74 @@
+use std::cell::Cell;
+
+bitflags! {
+    // Standard controller buttons, in the order the shift register reports them
+    // from the most significant bit:
+    // R L D U T S B A
+    #[derive(Default)]
+    pub struct JoypadButton: u8 {
+        const RIGHT     = 0b1000_0000;
+        const LEFT      = 0b0100_0000;
+        const DOWN      = 0b0010_0000;
+        const UP        = 0b0001_0000;
+        const START     = 0b0000_1000;
+        const SELECT    = 0b0000_0100;
+        const BUTTON_B  = 0b0000_0010;
+        const BUTTON_A  = 0b0000_0001;
+    }
+}
+
+// Standard controller as seen through $4016/$4017.
+// Writing 1 to the strobe bit reloads the shift register, each read then reports
+// one button starting with A. Reads past the eighth button return 1.
+pub struct Joypad {
+    strobe: bool,
+    button_index: Cell<u8>,
+    button_status: JoypadButton,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: Cell::new(0),
+            button_status: JoypadButton::empty(),
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index.set(0);
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let index = self.button_index.get();
+        if index > 7 {
+            return 1;
+        }
+        let response = (self.button_status.bits() >> index) & 1;
+        if !self.strobe {
+            self.button_index.set(index + 1);
+        }
+        response
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::joypad::JoypadButton;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    Reset,
+    SaveState,
+    LoadState,
+    // Runs one frame and stays paused
+    FrameAdvance,
+    // Held down rather than toggled
+    FastForward,
+    // Steps through full, half and quarter speed
+    SlowMotion,
+    Fullscreen,
+    Screenshot,
+    Capture,
+    ChrViewer,
+    ChrPalette,
+    ChrBank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    // Player index from 0
+    Button(usize, JoypadButton),
+    Hotkey(Hotkey),
+}
+
+const BUTTONS: [(&str, JoypadButton); 8] = [
+    ("up", JoypadButton::UP),
+    ("down", JoypadButton::DOWN),
+    ("left", JoypadButton::LEFT),
+    ("right", JoypadButton::RIGHT),
+    ("a", JoypadButton::BUTTON_A),
+    ("b", JoypadButton::BUTTON_B),
+    ("select", JoypadButton::SELECT),
+    ("start", JoypadButton::START),
+];
+
+const HOTKEYS: [(&str, Hotkey); 14] = [
+    ("quit", Hotkey::Quit),
+    ("pause", Hotkey::Pause),
+    ("reset", Hotkey::Reset),
+    ("save_state", Hotkey::SaveState),
+    ("load_state", Hotkey::LoadState),
+    ("frame_advance", Hotkey::FrameAdvance),
+    ("fast_forward", Hotkey::FastForward),
+    ("slow_motion", Hotkey::SlowMotion),
+    ("fullscreen", Hotkey::Fullscreen),
+    ("screenshot", Hotkey::Screenshot),
+    ("capture", Hotkey::Capture),
+    ("chr_viewer", Hotkey::ChrViewer),
+    ("chr_palette", Hotkey::ChrPalette),
+    ("chr_bank", Hotkey::ChrBank),
+];
+
+// Keys are named the way SDL names them, the frontend checks they exist
+pub const DEFAULT_BINDINGS: &str = r#"
+[player1]
+up = "W"
+down = "S"
+left = "A"
+right = "D"
+a = "K"
+b = "J"
+select = "Right Shift"
+start = "Return"
+
+[player2]
+up = "Up"
+down = "Down"
+left = "Left"
+right = "Right"
+a = "Keypad 2"
+b = "Keypad 1"
+select = "Keypad 4"
+start = "Keypad 5"
+
+# Players 3 and 4 are read through a Four Score or Famicom adapter
+[player3]
+up = "T"
+down = "G"
+left = "F"
+right = "H"
+a = "U"
+b = "Y"
+select = "V"
+start = "B"
+
+[player4]
+up = "Home"
+down = "End"
+left = "Delete"
+right = "PageDown"
+a = "PageUp"
+b = "Insert"
+select = "Keypad 7"
+start = "Keypad 8"
+
+[hotkeys]
+quit = "Escape"
+pause = "P"
+reset = "F1"
+save_state = "F5"
+load_state = "F7"
+frame_advance = "\\"
+fast_forward = "Tab"
+slow_motion = "F6"
+fullscreen = "F11"
+screenshot = "F12"
+capture = "F9"
+chr_viewer = "F2"
+chr_palette = "F3"
+chr_bank = "F4"
+"#;
+
+// What each key does. A config file lists `action = "Key"` or
+// `action = ["Key", "Other key"]` under [player1] to [player4] and [hotkeys].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBindings {
+    keys: HashMap<String, Binding>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let mut bindings = KeyBindings { keys: HashMap::new() };
+        bindings.apply(DEFAULT_BINDINGS).unwrap();
+        bindings
+    }
+}
+
+impl KeyBindings {
+    // Actions the file leaves out keep their default keys
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<KeyBindings, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        KeyBindings::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<KeyBindings, String> {
+        let mut bindings = KeyBindings::default();
+        bindings.apply(text)?;
+        Ok(bindings)
+    }
+
+    // Key names with what they do, for the frontend to translate into its keycodes
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Binding)> {
+        self.keys.iter().map(|(key, binding)| (key.as_str(), *binding))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Binding> {
+        self.keys.get(&key.to_lowercase()).copied()
+    }
+
+    fn apply(&mut self, text: &str) -> Result<(), String> {
+        let table: toml::value::Table = toml::from_str(text).map_err(|err| err.to_string())?;
+        let mut assigned: HashMap<String, Binding> = HashMap::new();
+
+        for (section, entries) in &table {
+            let entries = entries.as_table().ok_or_else(|| format!("[{}] is not a section", section))?;
+            for (action, keys) in entries {
+                let binding = parse_binding(section, action)?;
+                // A rebound action drops its old keys
+                self.keys.retain(|_, existing| *existing != binding);
+                for key in key_names(keys).ok_or_else(|| format!("{}.{} takes a key name or a list of them", section, action))? {
+                    let key = key.to_lowercase();
+                    if let Some(other) = assigned.insert(key.clone(), binding) {
+                        if other != binding {
+                            return Err(format!("key {:?} is bound twice", key));
+                        }
+                    }
+                }
+            }
+        }
+        // Keys taken by the file override whatever used them before
+        self.keys.extend(assigned);
+        Ok(())
+    }
+}
+
+fn parse_binding(section: &str, action: &str) -> Result<Binding, String> {
+    let player = match section {
+        "hotkeys" => {
+            return HOTKEYS
+                .iter()
+                .find(|(name, _)| *name == action)
+                .map(|&(_, hotkey)| Binding::Hotkey(hotkey))
+                .ok_or_else(|| format!("unknown hotkey {:?}", action));
+        }
+        "player1" => 0,
+        "player2" => 1,
+        "player3" => 2,
+        "player4" => 3,
+        _ => return Err(format!("unknown section [{}], expected player1 to player4 or hotkeys", section)),
+    };
+    BUTTONS
+        .iter()
+        .find(|(name, _)| *name == action)
+        .map(|&(_, button)| Binding::Button(player, button))
+        .ok_or_else(|| format!("unknown button {:?} in [{}]", action, section))
+}
+
+fn key_names(value: &toml::Value) -> Option<Vec<&str>> {
+    match value {
+        toml::Value::String(key) => Some(vec![key.as_str()]),
+        toml::Value::Array(keys) => keys.iter().map(toml::Value::as_str).collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bindings_override_defaults() {
+        let defaults = KeyBindings::default();
+        assert_eq!(defaults.get("w"), Some(Binding::Button(0, JoypadButton::UP)));
+        assert_eq!(defaults.get("Keypad 5"), Some(Binding::Button(1, JoypadButton::START)));
+        assert_eq!(defaults.get("Escape"), Some(Binding::Hotkey(Hotkey::Quit)));
+
+        let bindings = KeyBindings::parse("[player1]\nup = [\"Up\", \"I\"]\n[hotkeys]\npause = \"Space\"\n").unwrap();
+        assert_eq!(bindings.get("up"), Some(Binding::Button(0, JoypadButton::UP)));
+        assert_eq!(bindings.get("I"), Some(Binding::Button(0, JoypadButton::UP)));
+        assert_eq!(bindings.get("W"), None);
+        assert_eq!(bindings.get("P"), None);
+        assert_eq!(bindings.get("space"), Some(Binding::Hotkey(Hotkey::Pause)));
+        assert_eq!(bindings.get("S"), Some(Binding::Button(0, JoypadButton::DOWN)));
+
+        assert_eq!(defaults.get("Home"), Some(Binding::Button(3, JoypadButton::UP)));
+        assert!(KeyBindings::parse("[player5]\nup = \"W\"").is_err());
+        assert!(KeyBindings::parse("[player1]\njump = \"W\"").is_err());
+        assert!(KeyBindings::parse("[hotkeys]\nrewind = \"R\"").is_err());
+        assert!(KeyBindings::parse("[player1]\nup = 5").is_err());
+        assert!(KeyBindings::parse("[player1]\nup = \"X\"\ndown = \"X\"").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+// Emulator core, free of any frontend dependency.
+// The SDL frontend in main.rs is built only with the `sdl` feature.
+pub mod battery;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod cpu;
+pub mod dbginfo;
+pub mod debugger;
+pub mod disasm;
+pub mod font;
+pub mod fourscore;
+pub mod joypad;
+pub mod keymap;
+pub mod movie;
+pub mod ntsc;
+pub mod observer;
+pub mod opcodes;
+pub mod palette;
+pub mod ppuview;
+pub mod profiler;
+pub mod ramsearch;
+pub mod recorder;
+pub mod region;
+pub mod screenshot;
+pub mod script;
+pub mod snake;
+pub mod symbols;
+pub mod viewport;
+pub mod zapper;
+
+#[macro_use]
+extern crate bitflags;
@@ -1,190 +1,818 @@
-pub mod cpu;
-pub mod opcodes;
-use cpu::CPU;
-use cpu::MEM;
-use rand::Rng;
-use sdl2::{event::Event, keyboard::Keycode, pixels::{Color, PixelFormatEnum}, EventPump};
-
-#[macro_use]
-extern crate bitflags;
-
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
-    }
-}
-
-// Memory mapping used by the game:
-/* 0xFE - Input - Random Number Generator
- * 0xFF - Input - Code of the last pressed button
- * [0x0200..0x0600] - Output - Screen
- * Each cell represents the color of a pixel in a 32x32 matrix.
- * The matrix starts from top-left corner i.e.
- * 0x0200 - color of (0, 0) pixel
- * 0x0201 - color of (1, 0) pixel
- * 0x0220 - color of (0, 1) pixel
- * ...
- * [0x0600..] - Game Code - Execution Code
- * The game expects the execution code to be located right after the output region
- */
+use std::collections::HashMap;
+use std::env;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nes_core::battery::BatterySave;
+use nes_core::cartridge::Rom;
+use nes_core::cdl::CodeDataLogger;
+use nes_core::cheats::{cheat_path_for_rom, CheatList};
+use nes_core::cpu::{CpuError, CpuState, CPU};
+use nes_core::dbginfo::DebugInfo;
+use nes_core::debugger::Debugger;
+use nes_core::disasm;
+use nes_core::font;
+use nes_core::fourscore::Multitap;
+use nes_core::keymap::{Binding, Hotkey, KeyBindings};
+use nes_core::movie::{self, Movie, StartCondition, COMMAND_SOFT_RESET};
+use nes_core::ntsc::{NtscFilter, NtscFilterParams, NtscPreset};
+use nes_core::palette::Palette;
+use nes_core::ppuview;
+use nes_core::profiler::Profiler;
+use nes_core::recorder::{Recorder, VideoFormat};
+use nes_core::region::{FrameClock, Region};
+use nes_core::screenshot;
+use nes_core::script::ScriptHost;
+use nes_core::snake;
+use nes_core::viewport::{self, Fit};
+use nes_core::zapper::PortDevice;
+use nes_core::symbols::SymbolTable;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sdl2::{
+    event::{Event, WindowEvent}, keyboard::Keycode, mouse::MouseButton, pixels::PixelFormatEnum, rect::Rect, render::{Texture, WindowCanvas}, video::FullscreenType, EventPump, VideoSubsystem,
+};
+
+// Movies need the apple positions to repeat, so they always run from the same seed
+const MOVIE_SEED: u64 = 0;
+// Battery RAM is flushed every few seconds so a crash loses little progress
+const SAVE_RAM_FLUSH_FRAMES: usize = 300;
+const CAPTURE_SAMPLE_RATE: u32 = 44100;
+const SCRIPT_TEXT_COLOR: (u8, u8, u8) = (255, 255, 255);
+// Read when present and no other file is given with --keys
+const DEFAULT_KEYS_PATH: &str = "keys.toml";
+
+const USAGE: &str = "\
+usage: nes_emulator [options]
+  --rom <game.nes>                    run a ROM instead of the snake demo
+  --record <movie.fm2>                record input to a movie
+  --play <movie.fm2>                  play back a movie
+  --cheats <file.cht>                 load cheat codes, <rom>.cht when there is one
+  --palette <file.pal>                load a 192 or 1536 byte palette
+  --filter <composite|svideo|rgb>     NTSC video filter
+  --region <ntsc|pal|dendy>           console timing, detected from the ROM header by default
+  --script <file.rhai>                run a script with memory, CPU and frame hooks
+  --cdl <file.cdl>                    log code and data use of the ROM, adding to an existing log
+  --profile <base>                    write a profile to <base>.txt and flamegraph stacks to <base>.folded
+  --symbols <file>                    load labels from a .dbg, .nl, .mlb or addr=name file, may be repeated;
+                                      .dbg files also give the debugger source lines
+  --keys <file.toml>                  key bindings, keys.toml in the current directory by default
+  --scale <1-8>                       window size in multiples of the 256x240 NES picture, 2 by default
+  --fit <integer|aspect>              whole multiples with square pixels, or the 8:7 pixels of a television
+  --overscan                          crop the top and bottom 8 lines of 240 line NES output
+  --port2 <joypad|zapper>             device in controller port 2, the zapper aims with the mouse
+  --multitap <fourscore|famicom>      connect players 3 and 4 through a Four Score or Famicom adapter
+Default keys: WASD, J, K, Right Shift and Return for player 1, the arrows and keypad for player 2,
+TFGH, U, Y, V and B for player 3, Home, End, Delete, PageDown, PageUp, Insert and keypad 7 and 8 for player 4.
+Escape quits, P pauses, \\ advances one frame, Tab fast-forwards while held, F6 switches to 50% and 25% speed,
+F1 resets, F5 and F7 save and load a state to <game>.state, F11 toggles fullscreen,
+F12 saves a screenshot to <game>-<n>.png, F9 starts and stops capturing video and audio to
+<game>-<n>.y4m and .wav, F2 shows the CHR ROM pattern tables, F3 changes their colours and F4 the CHR bank";
+
+struct Options {
+    rom: Option<String>,
+    record: Option<String>,
+    play: Option<String>,
+    cheats: Option<String>,
+    palette: Option<String>,
+    filter: Option<NtscPreset>,
+    region: Option<Region>,
+    script: Option<String>,
+    cdl: Option<String>,
+    profile: Option<String>,
+    symbols: Vec<String>,
+    keys: Option<String>,
+    scale: u32,
+    fit: Fit,
+    overscan: bool,
+    port2: PortDevice,
+    multitap: Multitap,
+}
+
+fn parse_options() -> Options {
+    let mut options = Options { rom: None, record: None, play: None, cheats: None, palette: None, filter: None, region: None, script: None, cdl: None, profile: None, symbols: Vec::new(), keys: None, scale: 2, fit: Fit::Integer, overscan: false, port2: PortDevice::Joypad, multitap: Multitap::Off };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rom" => options.rom = args.next(),
+            "--record" => options.record = args.next(),
+            "--play" => options.play = args.next(),
+            "--cheats" => options.cheats = args.next(),
+            "--palette" => options.palette = args.next(),
+            "--script" => options.script = args.next(),
+            "--cdl" => options.cdl = args.next(),
+            "--profile" => options.profile = args.next(),
+            "--symbols" => options.symbols.extend(args.next()),
+            "--keys" => options.keys = args.next(),
+            "--overscan" => options.overscan = true,
+            "--multitap" => options.multitap = args.next().and_then(|name| Multitap::from_name(&name)).unwrap_or_else(|| {
+                eprintln!("--multitap takes fourscore, famicom or off");
+                process::exit(2);
+            }),
+            "--port2" => options.port2 = args.next().and_then(|name| PortDevice::from_name(&name)).unwrap_or_else(|| {
+                eprintln!("--port2 takes joypad or zapper");
+                process::exit(2);
+            }),
+            "--scale" => options.scale = args.next().and_then(|scale| scale.parse().ok()).filter(|scale| (1..=8).contains(scale)).unwrap_or_else(|| {
+                eprintln!("--scale takes 1 to 8");
+                process::exit(2);
+            }),
+            "--fit" => options.fit = args.next().and_then(|name| Fit::from_name(&name)).unwrap_or_else(|| {
+                eprintln!("--fit takes integer or aspect");
+                process::exit(2);
+            }),
+            "--region" => options.region = args.next().and_then(|name| Region::from_name(&name)).or_else(|| {
+                eprintln!("--region takes ntsc, pal or dendy");
+                process::exit(2);
+            }),
+            "--filter" => options.filter = args.next().and_then(|name| NtscPreset::from_name(&name)).or_else(|| {
+                eprintln!("--filter takes composite, svideo or rgb");
+                process::exit(2);
+            }),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+    options
+}
 
 fn main() {
+    let options = parse_options();
+    let keys = load_key_bindings(options.keys.as_deref()).unwrap_or_else(|err| {
+        eprintln!("Cannot load key bindings {}", err);
+        process::exit(1);
+    });
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let (_, nes_lines) = viewport::visible_lines(viewport::NES_HEIGHT, options.overscan);
+    let (window_width, window_height) = options.fit.scaled((viewport::NES_WIDTH, nes_lines), options.scale);
     let window = video_subsystem
-        .window("NES Emulator", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
+        .window("NES Emulator", window_width, window_height)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
 
+    let palette = match &options.palette {
+        Some(path) => Palette::load(path).unwrap_or_else(|err| {
+            eprintln!("Cannot load palette {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => Palette::default(),
+    };
+
+    let mut filter = options.filter.map(|preset| NtscFilter::new(NtscFilterParams::preset(preset), &palette));
+    let texture_width = match filter {
+        Some(_) => NtscFilter::output_width(snake::SCREEN_WIDTH),
+        None => snake::SCREEN_WIDTH,
+    };
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
+        .create_texture_target(PixelFormatEnum::RGB24, texture_width as u32, snake::SCREEN_HEIGHT as u32)
         .unwrap();
+    // The NTSC filter widens the texture, the picture keeps its console proportions
+    let (first_line, lines) = viewport::visible_lines(snake::SCREEN_HEIGHT as u32, options.overscan);
+    let source = Rect::new(0, first_line as i32, texture_width as u32, lines);
+    let picture = (snake::SCREEN_WIDTH as u32, lines);
+    let mut placement = (0, 0, 1, 1);
 
-    let game_code = vec![
-        0x20, 0x06, 0x06, 0x20, 0x38, 
-        0x06, 0x20, 0x0d, 0x06, 0x20, 
-        0x2a, 0x06, 0x60, 0xa9, 0x02, 
-        0x85, 0x02, 0xa9, 0x04, 0x85, 
-        0x03, 0xa9, 0x11, 0x85, 0x10, 
-        0xa9, 0x10, 0x85, 0x12, 0xa9, 
-        0x0f, 0x85, 0x14, 0xa9, 0x04, 
-        0x85, 0x11, 0x85, 0x13, 0x85, 
-        0x15, 0x60, 0xa5, 0xfe, 0x85, 
-        0x00, 0xa5, 0xfe, 0x29, 0x03, 
-        0x18, 0x69, 0x02, 0x85, 0x01, 
-        0x60, 0x20, 0x4d, 0x06, 0x20, 
-        0x8d, 0x06, 0x20, 0xc3, 0x06, 
-        0x20, 0x19, 0x07, 0x20, 0x20, 
-        0x07, 0x20, 0x2d, 0x07, 0x4c, 
-        0x38, 0x06, 0xa5, 0xff, 0xc9, 
-        0x77, 0xf0, 0x0d, 0xc9, 0x64, 
-        0xf0, 0x14, 0xc9, 0x73, 0xf0, 
-        0x1b, 0xc9, 0x61, 0xf0, 0x22, 
-        0x60, 0xa9, 0x04, 0x24, 0x02, 
-        0xd0, 0x26, 0xa9, 0x01, 0x85, 
-        0x02, 0x60, 0xa9, 0x08, 0x24, 
-        0x02, 0xd0, 0x1b, 0xa9, 0x02, 
-        0x85, 0x02, 0x60, 0xa9, 0x01, 
-        0x24, 0x02, 0xd0, 0x10, 0xa9, 
-        0x04, 0x85, 0x02, 0x60, 0xa9, 
-        0x02, 0x24, 0x02, 0xd0, 0x05, 
-        0xa9, 0x08, 0x85, 0x02, 0x60, 
-        0x60, 0x20, 0x94, 0x06, 0x20, 
-        0xa8, 0x06, 0x60, 0xa5, 0x00, 
-        0xc5, 0x10, 0xd0, 0x0d, 0xa5, 
-        0x01, 0xc5, 0x11, 0xd0, 0x07, 
-        0xe6, 0x03, 0xe6, 0x03, 0x20, 
-        0x2a, 0x06, 0x60, 0xa2, 0x02, 
-        0xb5, 0x10, 0xc5, 0x10, 0xd0, 
-        0x06, 0xb5, 0x11, 0xc5, 0x11, 
-        0xf0, 0x09, 0xe8, 0xe8, 0xe4, 
-        0x03, 0xf0, 0x06, 0x4c, 0xaa, 
-        0x06, 0x4c, 0x35, 0x07, 0x60, 
-        0xa6, 0x03, 0xca, 0x8a, 0xb5, 
-        0x10, 0x95, 0x12, 0xca, 0x10, 
-        0xf9, 0xa5, 0x02, 0x4a, 0xb0, 
-        0x09, 0x4a, 0xb0, 0x19, 0x4a, 
-        0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 
-        0xa5, 0x10, 0x38, 0xe9, 0x20, 
-        0x85, 0x10, 0x90, 0x01, 0x60, 
-        0xc6, 0x11, 0xa9, 0x01, 0xc5, 
-        0x11, 0xf0, 0x28, 0x60, 0xe6, 
-        0x10, 0xa9, 0x1f, 0x24, 0x10, 
-        0xf0, 0x1f, 0x60, 0xa5, 0x10, 
-        0x18, 0x69, 0x20, 0x85, 0x10, 
-        0xb0, 0x01, 0x60, 0xe6, 0x11, 
-        0xa9, 0x06, 0xc5, 0x11, 0xf0, 
-        0x0c, 0x60, 0xc6, 0x10, 0xa5, 
-        0x10, 0x29, 0x1f, 0xc9, 0x1f, 
-        0xf0, 0x01, 0x60, 0x4c, 0x35, 
-        0x07, 0xa0, 0x00, 0xa5, 0xfe, 
-        0x91, 0x00, 0x60, 0xa6, 0x03, 
-        0xa9, 0x00, 0x81, 0x10, 0xa2, 
-        0x00, 0xa9, 0x01, 0x81, 0x10, 
-        0x60, 0xa2, 0x00, 0xea, 0xea, 
-        0xca, 0xd0, 0xfb, 0x60
-    ];
-    
-    // Load the game
+    // Load the game, the snake demo unless a ROM was given
     let mut cpu = CPU::new();
-    cpu.load(game_code);
+    let (game_name, game_data, mut battery, rom) = match &options.rom {
+        Some(path) => {
+            let raw = fs::read(path).unwrap_or_else(|err| {
+                eprintln!("Cannot read ROM {}: {}", path, err);
+                process::exit(1);
+            });
+            let rom = Rom::new(&raw).unwrap_or_else(|err| {
+                eprintln!("Cannot load ROM {}: {}", path, err);
+                process::exit(1);
+            });
+            if let Err(err) = cpu.load_rom(&rom) {
+                eprintln!("Cannot load ROM {}: {}", path, err);
+                process::exit(1);
+            }
+            let mut battery = BatterySave::new(path, &rom);
+            if let Some(save) = &mut battery {
+                if let Err(err) = save.load(&mut cpu) {
+                    eprintln!("Cannot load save RAM {}: {}", save.path().display(), err);
+                    process::exit(1);
+                }
+            }
+            // Codes kept next to the ROM, unless --cheats names another file
+            let cheat_path = cheat_path_for_rom(path);
+            if options.cheats.is_none() && cheat_path.exists() {
+                cpu.cheats = CheatList::load(&cheat_path).unwrap_or_else(|err| {
+                    eprintln!("Cannot load cheats {}: {}", cheat_path.display(), err);
+                    process::exit(1);
+                });
+            }
+            (path.clone(), raw, battery, Some(rom))
+        }
+        None => {
+            cpu.load(snake::GAME_CODE.to_vec()).unwrap();
+            ("snake".to_string(), snake::GAME_CODE.to_vec(), None, None)
+        }
+    };
+    let is_snake = options.rom.is_none();
+    let region = options.region.or(rom.as_ref().and_then(|rom| rom.region)).unwrap_or_default();
+    cpu.port2 = options.port2;
+    cpu.multitap = options.multitap;
     cpu.reset();
 
+    if let Some(path) = &options.cheats {
+        cpu.cheats = CheatList::load(path).unwrap_or_else(|err| {
+            eprintln!("Cannot load cheats {}: {}", path, err);
+            process::exit(1);
+        });
+    }
+
+    let mut playback = options.play.as_ref().map(|path| {
+        let movie = Movie::load_fm2(path).unwrap_or_else(|err| {
+            eprintln!("Cannot load movie {}: {}", path, err);
+            process::exit(1);
+        });
+        if !movie.matches_rom(&game_data) {
+            eprintln!("Movie {} was recorded on a different ROM, playback may desync", path);
+        }
+        if let Err(err) = movie.begin(&mut cpu) {
+            eprintln!("Cannot start movie {}: {}", path, err);
+            process::exit(1);
+        }
+        movie
+    });
+    let mut recording = options.record.as_ref().map(|_| {
+        let mut movie = Movie::new(&game_name, &game_data, StartCondition::PowerOn);
+        movie.pal = region == Region::Pal;
+        movie.four_score = cpu.multitap != Multitap::Off;
+        movie
+    });
+
+    // Loaded last, a movie start may reset the console and undo the script's setup
+    let mut script = options.script.as_ref().map(|path| {
+        ScriptHost::load(path, &mut cpu).unwrap_or_else(|err| {
+            eprintln!("Cannot load script {}: {}", path, err);
+            process::exit(1);
+        })
+    });
+
+    let mut rng = if playback.is_some() || recording.is_some() {
+        StdRng::seed_from_u64(MOVIE_SEED)
+    } else {
+        StdRng::from_entropy()
+    };
+
+    let cdl = options.cdl.as_ref().map(|path| {
+        let Some(rom) = &rom else {
+            eprintln!("--cdl needs a ROM given with --rom");
+            process::exit(2);
+        };
+        let logger = CodeDataLogger::load(path, rom).unwrap_or_else(|err| {
+            eprintln!("Cannot load CDL {}: {}", path, err);
+            process::exit(1);
+        });
+        let logger = Rc::new(RefCell::new(logger));
+        CodeDataLogger::attach(&logger, &mut cpu);
+        logger
+    });
+
+    let mut profiler = options.profile.as_ref().map(|_| Profiler::new());
 
+    let mut debugger = Debugger::new();
+    let mut symbols = SymbolTable::new(rom.as_ref().map_or(0, |rom| rom.prg_rom.len()));
+    for path in &options.symbols {
+        let loaded = symbols.load(path).and_then(|_| match path.ends_with(".dbg") {
+            true => DebugInfo::load(path, symbols.prg_len()).map(|info| debugger.set_debug_info(info)),
+            false => Ok(()),
+        });
+        if let Err(err) = loaded {
+            eprintln!("Cannot load symbols {}", err);
+            process::exit(1);
+        }
+    }
+    debugger.set_symbols(symbols);
+    let commands = spawn_command_reader();
 
-    let mut screen_state = [0 as u8; 32 * 32 * 3];
-    let mut rng = rand::thread_rng();
+    let mut screen_state = Vec::new();
+    let mut capture: Option<Recorder> = None;
+    let capture_stem = Path::new(&game_name).file_stem().map_or("capture".into(), |stem| stem.to_string_lossy());
+    let mut frame = 0;
+    let mut pacer = FramePacer::new(region.frame_rate());
+    let mut clock = FrameClock::new(region);
+    let mut chr_viewer: Option<ChrViewer> = None;
+    let mut fast_forward = false;
+    let mut frame_advance = false;
+    // Commands for the movie frame being recorded next, such as a reset
+    let mut movie_commands = 0;
+    let mut title = String::new();
+    let mut result = Ok(());
 
     // Run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        // Read user input and write it to mem[0xFF]
-        // Update mem[0xFE] with new Random number
-        // Read mem mapped screen state
+    'running: loop {
+        // Read user input or the movie into the controller port
+        // Translate controller state for the snake game
+        // Run the CPU for a frame, updating mem[0xFE] with new random numbers
         // Render screen state
-        handle_user_input(cpu, &mut event_pump);
-        cpu.mem_write(0xFE, rng.gen_range(1, 16));
+        for event in handle_user_input(&mut cpu, &mut event_pump, &keys, playback.is_none()) {
+            match event {
+                InputEvent::Pressed(Hotkey::Quit) => break 'running,
+                InputEvent::Pressed(Hotkey::Pause) => debugger.set_paused(!debugger.paused(), &cpu),
+                // Pauses first when running, each press then runs one more frame
+                InputEvent::Pressed(Hotkey::FrameAdvance) if !debugger.paused() => debugger.set_paused(true, &cpu),
+                InputEvent::Pressed(Hotkey::FrameAdvance) => frame_advance = true,
+                InputEvent::Pressed(Hotkey::SlowMotion) => pacer.next_speed(),
+                InputEvent::Pressed(Hotkey::Fullscreen) => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off,
+                    };
+                    if let Err(err) = window.set_fullscreen(fullscreen) {
+                        eprintln!("Cannot change fullscreen: {}", err);
+                    }
+                }
+                InputEvent::Pressed(Hotkey::Reset) => {
+                    cpu.reset();
+                    movie_commands |= COMMAND_SOFT_RESET;
+                }
+                InputEvent::Pressed(Hotkey::SaveState) => {
+                    let path = format!("{}.state", capture_stem);
+                    match fs::write(&path, cpu.save_state()) {
+                        Ok(()) => println!("State saved to {}", path),
+                        Err(err) => eprintln!("Cannot save state {}: {}", path, err),
+                    }
+                }
+                // A movie has no way to say a state was loaded, playback would desync
+                InputEvent::Pressed(Hotkey::LoadState) if recording.is_some() => {
+                    eprintln!("Cannot load a state while recording a movie");
+                }
+                InputEvent::Pressed(Hotkey::LoadState) => {
+                    let path = format!("{}.state", capture_stem);
+                    let loaded = fs::read(&path).map_err(|err| err.to_string())
+                        .and_then(|state| cpu.load_state(&state).map_err(|err| err.to_string()));
+                    match loaded {
+                        Ok(()) => println!("State loaded from {}", path),
+                        Err(err) => eprintln!("Cannot load state {}: {}", path, err),
+                    }
+                }
+                InputEvent::Pressed(Hotkey::FastForward) => fast_forward = true,
+                InputEvent::Released(Hotkey::FastForward) => fast_forward = false,
+                InputEvent::Pressed(Hotkey::Screenshot) => {
+                    let path = (1..)
+                        .map(|n| PathBuf::from(format!("{}-{}.png", capture_stem, n)))
+                        .find(|path| !path.exists())
+                        .unwrap();
+                    match screenshot::save_png(&path, texture_width, snake::SCREEN_HEIGHT, &screen_state) {
+                        Ok(()) => println!("Screenshot saved to {}", path.display()),
+                        Err(err) => eprintln!("Cannot save screenshot {}: {}", path.display(), err),
+                    }
+                }
+                InputEvent::MouseMove(id, x, y) if id == canvas.window().id() => {
+                    // Window position to a pixel of the texture, which the zapper looks at
+                    let (left, top, width, height) = placement;
+                    let (x, y) = (x - left, y - top);
+                    let inside = x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height;
+                    cpu.zapper.aim(inside.then(|| {
+                        let column = x as usize * texture_width / width as usize;
+                        let row = first_line as usize + y as usize * lines as usize / height as usize;
+                        (column, row)
+                    }));
+                }
+                InputEvent::MouseMove(..) => (),
+                InputEvent::Trigger(pulled) => cpu.zapper.trigger = pulled,
+                InputEvent::CloseWindow(id) => match &chr_viewer {
+                    Some(viewer) if viewer.canvas.window().id() == id => chr_viewer = None,
+                    _ => break 'running,
+                },
+                InputEvent::Pressed(Hotkey::ChrViewer) if chr_viewer.is_some() => chr_viewer = None,
+                InputEvent::Pressed(Hotkey::ChrViewer) => match rom.as_ref().filter(|rom| !rom.chr_rom.is_empty()) {
+                    Some(_) => match ChrViewer::open(&video_subsystem) {
+                        Ok(viewer) => chr_viewer = Some(viewer),
+                        Err(err) => eprintln!("Cannot open the pattern table viewer: {}", err),
+                    },
+                    None => eprintln!("No CHR ROM to show"),
+                },
+                InputEvent::Pressed(Hotkey::ChrPalette) => {
+                    if let Some(viewer) = &mut chr_viewer {
+                        viewer.colors = (viewer.colors + 1) % ppuview::VIEW_PALETTES.len();
+                    }
+                }
+                InputEvent::Pressed(Hotkey::ChrBank) => {
+                    if let (Some(viewer), Some(rom)) = (&mut chr_viewer, &rom) {
+                        viewer.bank = (viewer.bank + 1) % ppuview::chr_banks(&rom.chr_rom);
+                    }
+                }
+                InputEvent::Pressed(Hotkey::Capture) => match capture.take() {
+                    Some(recorder) => finish_capture(recorder),
+                    None => {
+                        let base = Recorder::next_free_base(&capture_stem);
+                        let started = Recorder::start(
+                            &base, VideoFormat::Y4m, texture_width, snake::SCREEN_HEIGHT,
+                            region.frame_rate_fraction(), CAPTURE_SAMPLE_RATE,
+                        );
+                        match started {
+                            Ok(recorder) => {
+                                println!("Capturing to {}", base.display());
+                                capture = Some(recorder);
+                            }
+                            Err(err) => eprintln!("Cannot start capture {}: {}", base.display(), err),
+                        }
+                    }
+                },
+                InputEvent::Released(_) => (),
+            }
+        }
+        // While the debugger is paused only commands run, the last frame stays up
+        run_debugger_commands(&mut debugger, &mut cpu, &commands);
+        let mut advancing = false;
+        if std::mem::take(&mut frame_advance) {
+            debugger.set_paused(false, &cpu);
+            advancing = true;
+        }
+        let paused = debugger.paused();
+        if !paused {
+            if !movie::frame_input(&mut cpu, frame, script.as_mut(), playback.as_ref(), recording.as_mut(), movie_commands) {
+                println!("Movie finished after {} frames, keyboard control restored", frame);
+                playback = None;
+            }
+            movie_commands = 0;
+            if is_snake {
+                snake::write_input(&mut cpu);
+            }
+
+            let rng = if is_snake { Some(&mut rng) } else { None };
+            let state = run_frame(&mut cpu, rng, &mut clock, script.as_mut(), profiler.as_mut(), &mut debugger);
+            if let Some(profiler) = &mut profiler {
+                profiler.end_frame(&cpu);
+            }
+            match state {
+                Ok(CpuState::Running) => (),
+                Ok(CpuState::Halted) => break,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+            if debugger.paused() {
+                println!("Breakpoint at {}", debugger.describe(cpu.program_counter));
+                println!("{}", disasm::trace_line(&cpu, debugger.symbols()));
+            } else if advancing {
+                debugger.set_paused(true, &cpu);
+            }
+            if let Some(err) = script.as_mut().and_then(|host| host.take_error()) {
+                eprintln!("Script stopped: {}", err);
+            }
+            if script.as_ref().is_some_and(|host| host.stop_requested()) {
+                break;
+            }
+            frame += 1;
+            if frame % SAVE_RAM_FLUSH_FRAMES == 0 {
+                flush_save_ram(&mut battery, &cpu);
+            }
+        }
+
+        let pixels = snake::screen_pixels(&cpu);
+        let mut rgb = match &mut filter {
+            Some(filter) => filter.apply(&pixels, snake::SCREEN_WIDTH),
+            None => palette.render(&pixels),
+        };
+        if cpu.port2 == PortDevice::Zapper {
+            cpu.zapper.sense(&rgb, texture_width, snake::SCREEN_HEIGHT);
+        }
+        if let Some(host) = &mut script {
+            for text in host.take_texts() {
+                font::draw_text(&mut rgb, texture_width, text.x, text.y, &text.text, SCRIPT_TEXT_COLOR);
+            }
+        }
+        // There is no APU yet, the recorder fills the audio track with silence
+        if let Some(recorder) = capture.as_mut().filter(|_| !paused) {
+            if let Err(err) = recorder.frame(&rgb, &[]) {
+                eprintln!("Capture stopped: {}", err);
+                capture = None;
+            }
+        }
+        if rgb != screen_state {
+            texture.update(None, &rgb, texture_width * 3).unwrap();
+            screen_state = rgb;
+        }
+        placement = options.fit.place(canvas.output_size().unwrap(), picture);
+        let (x, y, width, height) = placement;
+        canvas.clear();
+        canvas.copy(&texture, source, Rect::new(x, y, width, height)).unwrap();
+        canvas.present();
+        if let (Some(viewer), Some(rom)) = (&mut chr_viewer, &rom) {
+            viewer.draw(&rom.chr_rom, &palette);
+        }
+        let status = match (debugger.paused(), fast_forward) {
+            (true, _) => " - paused".to_string(),
+            (false, true) => " - fast-forward".to_string(),
+            (false, false) if pacer.speed() < 1.0 => format!(" - {}%", (pacer.speed() * 100.0) as u32),
+            (false, false) => String::new(),
+        };
+        if status != title {
+            canvas.window_mut().set_title(&format!("NES Emulator{}", status)).unwrap();
+            title = status;
+        }
+        // Fast-forward runs as fast as the host allows
+        if !fast_forward {
+            pacer.wait();
+        }
+    }
+
+    flush_save_ram(&mut battery, &cpu);
+    if let (Some(logger), Some(path)) = (&cdl, &options.cdl) {
+        if let Err(err) = logger.borrow().save(path) {
+            eprintln!("Cannot save CDL {}: {}", path, err);
+        }
+    }
+    if let Some(recorder) = capture {
+        finish_capture(recorder);
+    }
+    if let (Some(profiler), Some(base)) = (&profiler, &options.profile) {
+        save_profile(profiler, base, region);
+    }
+
+    // Keep the window up on a CPU error so the last frame can still be inspected
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        eprintln!(
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            cpu.program_counter, cpu.register_a, cpu.register_x, cpu.register_y,
+            cpu.status.bits(), cpu.stack_pointer, cpu.cycles,
+        );
+        canvas.window_mut().set_title(&format!("NES Emulator - {}", err)).unwrap();
+        while !handle_user_input(&mut cpu, &mut event_pump, &keys, false)
+            .iter()
+            .any(|event| matches!(event, InputEvent::Pressed(Hotkey::Quit) | InputEvent::CloseWindow(_)))
+        {
+            ::std::thread::sleep(std::time::Duration::from_millis(16));
+        }
+    }
+
+    if let (Some(movie), Some(path)) = (recording, options.record) {
+        match movie.save_fm2(&path) {
+            Ok(()) => println!("Recorded {} frames to {}", movie.frames.len(), path),
+            Err(err) => eprintln!("Cannot save movie {}: {}", path, err),
+        }
+    }
+}
+
+fn finish_capture(recorder: Recorder) {
+    let frames = recorder.frames();
+    match recorder.finish() {
+        Ok(()) => println!("Captured {} frames", frames),
+        Err(err) => eprintln!("Cannot finish capture: {}", err),
+    }
+}
 
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+fn save_profile(profiler: &Profiler, base: &str, region: Region) {
+    let base = Path::new(base);
+    let result = fs::File::create(base.with_extension("txt"))
+        .and_then(|file| profiler.write_report(io::BufWriter::new(file), region.vblank_cpu_cycles()))
+        .and_then(|()| fs::File::create(base.with_extension("folded")))
+        .and_then(|file| profiler.write_collapsed(io::BufWriter::new(file)));
+    match result {
+        Ok(()) => println!("Profile written to {}", base.with_extension("txt").display()),
+        Err(err) => eprintln!("Cannot write profile {}: {}", base.display(), err),
+    }
+}
+
+// Debugger commands are typed into the terminal and run between frames
+fn spawn_command_reader() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
         }
-        ::std::thread::sleep(std::time::Duration::new(0, 1_000));
     });
+    receiver
+}
+
+fn run_debugger_commands(debugger: &mut Debugger, cpu: &mut CPU, commands: &Receiver<String>) {
+    for line in commands.try_iter() {
+        match debugger.execute(cpu, &line) {
+            Ok(output) if output.is_empty() => (),
+            Ok(output) => println!("{}", output),
+            Err(message) => eprintln!("{}", message),
+        }
+    }
+}
+
+// Defaults, overridden by keys.toml when it exists or the file given on the command line
+fn load_key_bindings(path: Option<&str>) -> Result<HashMap<Keycode, Binding>, String> {
+    let bindings = match path {
+        Some(path) => KeyBindings::load(path).map_err(|err| format!("{}: {}", path, err))?,
+        None if Path::new(DEFAULT_KEYS_PATH).exists() => {
+            KeyBindings::load(DEFAULT_KEYS_PATH).map_err(|err| format!("{}: {}", DEFAULT_KEYS_PATH, err))?
+        }
+        None => KeyBindings::default(),
+    };
+    bindings
+        .iter()
+        .map(|(name, binding)| match Keycode::from_name(name) {
+            Some(keycode) => Ok((keycode, binding)),
+            None => Err(format!("unknown key {:?}", name)),
+        })
+        .collect()
+}
+
+fn flush_save_ram(battery: &mut Option<BatterySave>, cpu: &CPU) {
+    if let Some(save) = battery {
+        if let Err(err) = save.flush(cpu) {
+            eprintln!("Cannot write save RAM {}: {}", save.path().display(), err);
+        }
+    }
+}
+
+// Only the snake demo takes random numbers. ROMs run the cycles `clock` hands out.
+// Scripts with instruction or exec hooks and the profiler get called before every instruction.
+// A breakpoint stops the frame early, leaving the debugger paused.
+fn run_frame(
+    cpu: &mut CPU,
+    mut rng: Option<&mut StdRng>,
+    clock: &mut FrameClock,
+    script: Option<&mut ScriptHost>,
+    mut profiler: Option<&mut Profiler>,
+    debugger: &mut Debugger,
+) -> Result<CpuState, CpuError> {
+    let cycles = match rng {
+        Some(_) => snake::CYCLES_PER_FRAME,
+        None => clock.next_frame(),
+    };
+    let before = cpu.cycles;
+    let mut script = script.filter(|host| host.wants_instructions());
+    let breakpoints = debugger.has_breakpoints();
+    let snake = rng.is_some();
+    let state = if !snake && script.is_none() && profiler.is_none() && !breakpoints {
+        cpu.run_for_cycles(cycles)
+    } else {
+        cpu.run_for_cycles_while(cycles, |cpu| {
+            if breakpoints && debugger.check_breakpoint(cpu) {
+                return false;
+            }
+            if let Some(rng) = &mut rng {
+                snake::write_random(cpu, rng.gen_range(1, 16));
+            }
+            if let Some(host) = &mut script {
+                host.before_instruction(cpu);
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.before_instruction(cpu);
+            }
+            true
+        })
+    };
+    if !snake {
+        clock.frame_done(before, cpu.cycles);
+    }
+    state
+}
+
+// Sleeps until the next frame is due at the console's frame rate, slowed down
+// for slow motion
+struct FramePacer {
+    frame_rate: f64,
+    speed: usize,
+    frame_duration: Duration,
+    next_frame: Instant,
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+impl FramePacer {
+    const SPEEDS: [f64; 3] = [1.0, 0.5, 0.25];
+
+    fn new(frame_rate: f64) -> Self {
+        let frame_duration = Duration::from_secs_f64(1.0 / frame_rate);
+        FramePacer { frame_rate, speed: 0, frame_duration, next_frame: Instant::now() + frame_duration }
+    }
+
+    fn speed(&self) -> f64 {
+        Self::SPEEDS[self.speed]
+    }
+
+    // Full, half, quarter speed and back to full
+    fn next_speed(&mut self) {
+        self.speed = (self.speed + 1) % Self::SPEEDS.len();
+        self.frame_duration = Duration::from_secs_f64(1.0 / (self.frame_rate * self.speed()));
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if now < self.next_frame {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_duration {
+            // Running behind, catch up from here instead of rushing the missed frames
+            self.next_frame = now;
+        }
+        self.next_frame += self.frame_duration;
+    }
+}
+
+// Second window with the pattern tables of one CHR bank, redrawn every frame
+struct ChrViewer {
+    // Freed by SDL together with the canvas renderer
+    texture: Texture,
+    canvas: WindowCanvas,
+    colors: usize,
+    bank: usize,
+}
+
+impl ChrViewer {
+    const SCALE: u32 = 2;
+
+    fn open(video: &VideoSubsystem) -> Result<Self, String> {
+        let width = ppuview::PATTERN_VIEW_WIDTH as u32 * Self::SCALE;
+        let height = ppuview::PATTERN_VIEW_HEIGHT as u32 * Self::SCALE;
+        let window = video.window("Pattern tables", width, height).build().map_err(|err| err.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|err| err.to_string())?;
+        let texture = canvas
+            .texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGB24, ppuview::PATTERN_VIEW_WIDTH as u32, ppuview::PATTERN_VIEW_HEIGHT as u32)
+            .map_err(|err| err.to_string())?;
+        Ok(ChrViewer { texture, canvas, colors: 0, bank: 0 })
+    }
+
+    fn draw(&mut self, chr: &[u8], palette: &Palette) {
+        let pixels = ppuview::pattern_tables(chr, self.bank, ppuview::VIEW_PALETTES[self.colors]);
+        self.texture.update(None, &palette.render(&pixels), ppuview::PATTERN_VIEW_WIDTH * 3).unwrap();
+        let title = format!("Pattern tables - bank {}", self.bank);
+        if self.canvas.window().title() != title {
+            self.canvas.window_mut().set_title(&title).unwrap();
+        }
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputEvent {
+    Pressed(Hotkey),
+    Released(Hotkey),
+    // Closing one of several windows, SDL only quits when the last one goes
+    CloseWindow(u32),
+    // Mouse position in a window, for the zapper
+    MouseMove(u32, i32, i32),
+    Trigger(bool),
+}
+
+// Feeds controller keys to the joypads and returns the emulator hotkeys pressed and released
+fn handle_user_input(
+    cpu: &mut CPU,
+    event_pump: &mut EventPump,
+    keys: &HashMap<Keycode, Binding>,
+    keyboard_enabled: bool,
+) -> Vec<InputEvent> {
+    let mut events = Vec::new();
     for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                std::process::exit(0)
-            },
-            Event::KeyDown { keycode: Some(Keycode::W), .. } => {
-                cpu.mem_write(0xFF, 0x77);
-            },
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => {
-                cpu.mem_write(0xFF, 0x73);
-            },
-            Event::KeyDown { keycode: Some(Keycode::A), .. } => {
-                cpu.mem_write(0xFF, 0x61);
-            },
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => {
-                cpu.mem_write(0xFF, 0x64);
-            }
-            _ => { /* Do nothing */}
-        }
-    }
-}
-
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-
-    for i in 0x0200..0x0600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;   
-            update = true;
-        }
-        frame_idx += 3;
-    }
-    update
-}
\ No newline at end of file
+        let (keycode, pressed) = match event {
+            Event::Quit { .. } => {
+                events.push(InputEvent::Pressed(Hotkey::Quit));
+                continue;
+            }
+            Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                events.push(InputEvent::CloseWindow(window_id));
+                continue;
+            }
+            Event::MouseMotion { window_id, x, y, .. } => {
+                events.push(InputEvent::MouseMove(window_id, x, y));
+                continue;
+            }
+            Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => {
+                events.push(InputEvent::Trigger(true));
+                continue;
+            }
+            Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+                events.push(InputEvent::Trigger(false));
+                continue;
+            }
+            Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => (keycode, true),
+            Event::KeyUp { keycode: Some(keycode), .. } => (keycode, false),
+            _ => continue,
+        };
+        match keys.get(&keycode) {
+            Some(Binding::Hotkey(hotkey)) if pressed => events.push(InputEvent::Pressed(*hotkey)),
+            Some(Binding::Hotkey(hotkey)) => events.push(InputEvent::Released(*hotkey)),
+            Some(Binding::Button(player, button)) if keyboard_enabled => {
+                if let Some(joypad) = cpu.joypad_mut(*player) {
+                    joypad.set_button_pressed_status(*button, pressed);
+                }
+            }
+            _ => (),
+        }
+    }
+    events
+}
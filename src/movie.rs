@@ -0,0 +1,475 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cpu::{CpuError, CPU};
+use crate::fourscore::Multitap;
+use crate::joypad::JoypadButton;
+use crate::script::ScriptHost;
+
+// Command bits of an FM2 input line
+pub const COMMAND_SOFT_RESET: u8 = 0b0000_0001;
+pub const COMMAND_HARD_RESET: u8 = 0b0000_0010;
+
+// Button columns of an FM2 gamepad field, most significant bit first
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+const FM2_VERSION: u32 = 3;
+const FM2_PORT_NONE: u8 = 0;
+const FM2_PORT_GAMEPAD: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartCondition {
+    PowerOn,
+    // A state produced by `CPU::save_state`
+    SaveState(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameInput {
+    pub commands: u8,
+    // Joypads 3 and 4 are only recorded with a Four Score
+    pub joypads: [JoypadButton; 4],
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    Unsupported(String),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f, "{}", err),
+            MovieError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::Unsupported(what) => write!(f, "unsupported movie feature: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> Self {
+        MovieError::Io(err)
+    }
+}
+
+// Controller input for every frame, tied to the ROM it was recorded on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_name: String,
+    pub rom_hash: [u8; 16],
+    pub start: StartCondition,
+    pub pal: bool,
+    pub four_score: bool,
+    pub rerecord_count: u32,
+    pub guid: String,
+    pub comments: Vec<String>,
+    pub frames: Vec<FrameInput>,
+}
+
+impl Movie {
+    pub fn new(rom_name: &str, rom: &[u8], start: StartCondition) -> Self {
+        Movie {
+            rom_name: rom_name.to_string(),
+            rom_hash: Movie::rom_hash(rom),
+            start,
+            pal: false,
+            four_score: false,
+            rerecord_count: 0,
+            guid: new_guid(),
+            comments: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    // MD5 of the ROM image, the same digest FCEUX stores as romChecksum
+    pub fn rom_hash(rom: &[u8]) -> [u8; 16] {
+        md5::compute(rom).0
+    }
+
+    pub fn matches_rom(&self, rom: &[u8]) -> bool {
+        self.rom_hash == Movie::rom_hash(rom)
+    }
+
+    // Puts a freshly loaded CPU into the state the recording started from,
+    // plugging in a Four Score when the movie used one
+    pub fn begin(&self, cpu: &mut CPU) -> Result<(), CpuError> {
+        if self.four_score && cpu.multitap == Multitap::Off {
+            cpu.multitap = Multitap::FourScore;
+        }
+        match &self.start {
+            StartCondition::PowerOn => {
+                cpu.reset();
+                Ok(())
+            }
+            StartCondition::SaveState(state) => cpu.load_state(state),
+        }
+    }
+
+    pub fn record_frame(&mut self, cpu: &CPU, commands: u8) {
+        let mut joypads = [cpu.joypad1.buttons(), cpu.joypad2.buttons(), cpu.joypad3.buttons(), cpu.joypad4.buttons()];
+        if !self.four_score {
+            joypads[2..].fill(JoypadButton::empty());
+        }
+        self.frames.push(FrameInput { commands, joypads });
+    }
+
+    // Drives the controller ports with the input of `frame`, false once the movie has ended
+    pub fn apply_frame(&self, frame: usize, cpu: &mut CPU) -> bool {
+        let input = match self.frames.get(frame) {
+            Some(input) => input,
+            None => return false,
+        };
+
+        // There is no power cycle short of rebuilding the CPU, a hard reset acts as a soft one
+        if input.commands & (COMMAND_SOFT_RESET | COMMAND_HARD_RESET) != 0 {
+            cpu.reset();
+        }
+        for (player, &buttons) in input.joypads.iter().enumerate() {
+            if let Some(joypad) = cpu.joypad_mut(player) {
+                joypad.set_buttons(buttons);
+            }
+        }
+        true
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut fm2 = String::new();
+        fm2.push_str(&format!("version {}\n", FM2_VERSION));
+        fm2.push_str("emuVersion 22020\n");
+        fm2.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        fm2.push_str(&format!("palFlag {}\n", self.pal as u8));
+        fm2.push_str(&format!("romFilename {}\n", self.rom_name));
+        fm2.push_str(&format!("romChecksum base64:{}\n", base64::encode(self.rom_hash)));
+        fm2.push_str(&format!("guid {}\n", self.guid));
+        fm2.push_str(&format!("fourscore {}\n", self.four_score as u8));
+        fm2.push_str("microphone 0\n");
+        fm2.push_str(&format!("port0 {}\n", FM2_PORT_GAMEPAD));
+        fm2.push_str(&format!("port1 {}\n", FM2_PORT_GAMEPAD));
+        fm2.push_str(&format!("port2 {}\n", FM2_PORT_NONE));
+        fm2.push_str("FDS 0\n");
+        fm2.push_str("NewPPU 0\n");
+        for comment in self.comments.iter() {
+            fm2.push_str(&format!("comment {}\n", comment));
+        }
+        if let StartCondition::SaveState(state) = &self.start {
+            fm2.push_str(&format!("savestate base64:{}\n", base64::encode(state)));
+        }
+
+        // With a Four Score every line holds four gamepads before the port2 field
+        let pads = if self.four_score { 4 } else { 2 };
+        for frame in self.frames.iter() {
+            let fields: Vec<String> = frame.joypads[..pads].iter().map(|&buttons| fm2_pad(buttons)).collect();
+            fm2.push_str(&format!("|{}|{}||\n", frame.commands, fields.join("|")));
+        }
+        fm2
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie {
+            rom_name: String::new(),
+            rom_hash: [0; 16],
+            start: StartCondition::PowerOn,
+            pal: false,
+            four_score: false,
+            rerecord_count: 0,
+            guid: String::new(),
+            comments: Vec::new(),
+            frames: Vec::new(),
+        };
+        let mut ports = [FM2_PORT_GAMEPAD, FM2_PORT_GAMEPAD];
+
+        for (idx, line) in text.lines().enumerate() {
+            let line_number = idx + 1;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('|') {
+                let ports = if movie.four_score { &[FM2_PORT_GAMEPAD; 4][..] } else { &ports[..] };
+                movie.frames.push(parse_input_line(line, ports, line_number)?);
+                continue;
+            }
+
+            let (key, value) = match line.find(' ') {
+                Some(split) => (&line[..split], &line[split + 1..]),
+                None => (line, ""),
+            };
+            let parse_error = |message: &str| MovieError::Parse {
+                line: line_number,
+                message: format!("{}: {}", key, message),
+            };
+
+            match key {
+                "version" if value != FM2_VERSION.to_string() => {
+                    return Err(MovieError::Unsupported(format!("FM2 version {}", value)));
+                }
+                "rerecordCount" => {
+                    movie.rerecord_count = value.parse().map_err(|_| parse_error("not a number"))?;
+                }
+                "palFlag" => movie.pal = value == "1",
+                "romFilename" => movie.rom_name = value.to_string(),
+                "romChecksum" => {
+                    let hash = decode_bytes(value).ok_or_else(|| parse_error("invalid checksum"))?;
+                    if hash.len() != 16 {
+                        return Err(parse_error("checksum is not an MD5 digest"));
+                    }
+                    movie.rom_hash.copy_from_slice(&hash);
+                }
+                "guid" => movie.guid = value.to_string(),
+                "comment" => movie.comments.push(value.to_string()),
+                "savestate" => {
+                    let state = decode_bytes(value).ok_or_else(|| parse_error("invalid save state"))?;
+                    // Other emulators' states cannot be loaded here
+                    if !CPU::is_save_state(&state) {
+                        return Err(MovieError::Unsupported("save state from another emulator".to_string()));
+                    }
+                    movie.start = StartCondition::SaveState(state);
+                }
+                "port0" | "port1" => {
+                    let port = value.parse::<u8>().map_err(|_| parse_error("not a number"))?;
+                    if port != FM2_PORT_NONE && port != FM2_PORT_GAMEPAD {
+                        return Err(MovieError::Unsupported(format!("{} device {}", key, port)));
+                    }
+                    ports[if key == "port0" { 0 } else { 1 }] = port;
+                }
+                "fourscore" => movie.four_score = value == "1",
+                "binary" | "FDS" if value != "0" => {
+                    return Err(MovieError::Unsupported(key.to_string()));
+                }
+                // Informational or irrelevant to this emulator
+                _ => (),
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn save_fm2<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        fs::write(path, self.to_fm2())?;
+        Ok(())
+    }
+
+    pub fn load_fm2<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::from_fm2(&fs::read_to_string(path)?)
+    }
+}
+
+fn fm2_pad(buttons: JoypadButton) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(idx, &name)| {
+            if buttons.bits() & (0x80 >> idx) != 0 { name as char } else { '.' }
+        })
+        .collect()
+}
+
+// `ports` has the device of each gamepad field, two or four of them
+fn parse_input_line(line: &str, ports: &[u8], line_number: usize) -> Result<FrameInput, MovieError> {
+    let parse_error = |message: &str| MovieError::Parse {
+        line: line_number,
+        message: message.to_string(),
+    };
+
+    // |commands|port0|port1|port2|, or four gamepads before port2 with a Four Score
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < ports.len() + 3 {
+        return Err(parse_error(&format!("input line needs a command field and {} port fields", ports.len() + 1)));
+    }
+
+    let mut input = FrameInput {
+        commands: fields[1].trim().parse().map_err(|_| parse_error("invalid command field"))?,
+        ..FrameInput::default()
+    };
+    for (port, field) in fields[2..2 + ports.len()].iter().enumerate() {
+        if ports[port] == FM2_PORT_NONE {
+            continue;
+        }
+        if field.len() != FM2_BUTTONS.len() {
+            return Err(parse_error("gamepad field must have 8 buttons"));
+        }
+        // Anything other than '.' or ' ' marks a pressed button
+        let bits = field
+            .bytes()
+            .enumerate()
+            .filter(|(_, c)| *c != b'.' && *c != b' ')
+            .fold(0u8, |bits, (idx, _)| bits | (0x80 >> idx));
+        input.joypads[port] = JoypadButton::from_bits_truncate(bits);
+    }
+    Ok(input)
+}
+
+// FCEUX writes binary values either as "base64:..." or as "0x" prefixed hex
+fn decode_bytes(value: &str) -> Option<Vec<u8>> {
+    if let Some(encoded) = value.strip_prefix("base64:") {
+        return base64::decode(encoded).ok();
+    }
+    let hex = value.strip_prefix("0x")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+fn new_guid() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or(0);
+    let hex: String = md5::compute(now.to_le_bytes())
+        .0
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+// Sets up the controller ports for one frame. The script's frame hook runs first
+// so the joypads it sets are recorded, and a movie being played back overrides it.
+// Returns false once the played back movie has no frames left.
+pub fn frame_input(
+    cpu: &mut CPU,
+    frame: usize,
+    script: Option<&mut ScriptHost>,
+    playback: Option<&Movie>,
+    recording: Option<&mut Movie>,
+    commands: u8,
+) -> bool {
+    if let Some(host) = script {
+        host.frame(cpu);
+    }
+    let playing = playback.is_none_or(|movie| movie.apply_frame(frame, cpu));
+    if let Some(movie) = recording {
+        movie.record_frame(cpu, commands);
+    }
+    playing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let mut movie = Movie::new("snake", &[0xa9, 0x01, 0x00], StartCondition::PowerOn);
+        movie.comments.push("author tester".to_string());
+        movie.frames.push(FrameInput::default());
+        movie.frames.push(FrameInput {
+            commands: COMMAND_SOFT_RESET,
+            joypads: [JoypadButton::UP | JoypadButton::BUTTON_A, JoypadButton::START, JoypadButton::empty(), JoypadButton::empty()],
+        });
+
+        let fm2 = movie.to_fm2();
+        assert!(fm2.contains("|0|........|........||\n"));
+        assert!(fm2.contains("|1|...U...A|....T...||\n"));
+
+        let parsed = Movie::from_fm2(&fm2).unwrap();
+        assert_eq!(parsed, movie);
+        assert!(parsed.matches_rom(&[0xa9, 0x01, 0x00]));
+        assert!(!parsed.matches_rom(&[0xa9, 0x02, 0x00]));
+
+        movie.four_score = true;
+        movie.frames[0].joypads[3] = JoypadButton::SELECT;
+        let fm2 = movie.to_fm2();
+        assert!(fm2.contains("fourscore 1\n"));
+        assert!(fm2.contains("|0|........|........|........|.....S..||\n"));
+        assert_eq!(Movie::from_fm2(&fm2).unwrap(), movie);
+
+        movie.start = StartCondition::SaveState(CPU::new().save_state());
+        assert_eq!(Movie::from_fm2(&movie.to_fm2()).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_fm2_import_fceux_movie() {
+        let fm2 = "version 3\r\nemuVersion 20604\r\nrerecordCount 7\r\npalFlag 0\r\n\
+                   romFilename Some Game\r\nromChecksum base64:AAECAwQFBgcICQoLDA0ODw==\r\n\
+                   guid 1C2F0D45-9A7E-4B2A-8B66-3C3F2A1B0E11\r\nport0 1\r\nport1 0\r\nport2 0\r\n\
+                   |0|R......A|||\r\n|2|.L.U xyz|||\r\n";
+        let movie = Movie::from_fm2(fm2).unwrap();
+
+        assert_eq!(movie.rom_name, "Some Game");
+        assert_eq!(movie.rerecord_count, 7);
+        assert_eq!(movie.rom_hash, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(movie.frames.len(), 2);
+        assert_eq!(movie.frames[0].joypads[0], JoypadButton::RIGHT | JoypadButton::BUTTON_A);
+        assert_eq!(movie.frames[1].commands, COMMAND_HARD_RESET);
+        assert_eq!(
+            movie.frames[1].joypads[0],
+            JoypadButton::LEFT | JoypadButton::UP | JoypadButton::SELECT | JoypadButton::BUTTON_B | JoypadButton::BUTTON_A
+        );
+        assert_eq!(movie.frames[1].joypads[1], JoypadButton::empty());
+    }
+
+    #[test]
+    fn test_fm2_rejects_unsupported_devices() {
+        let fm2 = "version 3\nport0 2\n";
+        assert!(matches!(Movie::from_fm2(fm2), Err(MovieError::Unsupported(_))));
+
+        let fm2 = "version 3\n|0|RLDU|........||\n";
+        assert!(matches!(Movie::from_fm2(fm2), Err(MovieError::Parse { line: 2, .. })));
+
+        let fm2 = "version 3\nsavestate base64:RkNTWAAA\n";
+        assert!(matches!(Movie::from_fm2(fm2), Err(MovieError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_playback_drives_controller_ports() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0x00]).unwrap();
+        let state = {
+            cpu.reset();
+            cpu.save_state()
+        };
+
+        let mut movie = Movie::new("test", &[0x00], StartCondition::SaveState(state));
+        cpu.joypad1.set_buttons(JoypadButton::DOWN);
+        movie.record_frame(&cpu, 0);
+        cpu.joypad1.set_buttons(JoypadButton::empty());
+        cpu.joypad2.set_buttons(JoypadButton::BUTTON_B);
+        movie.record_frame(&cpu, 0);
+
+        let mut replay = CPU::new();
+        movie.begin(&mut replay).unwrap();
+        assert_eq!(replay.program_counter, 0x0600);
+        assert!(movie.apply_frame(0, &mut replay));
+        assert_eq!(replay.joypad1.buttons(), JoypadButton::DOWN);
+        assert!(movie.apply_frame(1, &mut replay));
+        assert_eq!(replay.joypad1.buttons(), JoypadButton::empty());
+        assert_eq!(replay.joypad2.buttons(), JoypadButton::BUTTON_B);
+        assert!(!movie.apply_frame(2, &mut replay));
+    }
+
+    #[test]
+    fn test_scripted_input_is_recorded_and_played_back() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0x00]).unwrap();
+        cpu.reset();
+        let script = "emu::on_frame(|| joypad::set(1, if emu::frame() == 0 { joypad::A } else { joypad::START }));";
+        let mut host = ScriptHost::from_source(script, &mut cpu).unwrap();
+
+        let mut movie = Movie::new("test", &[0x00], StartCondition::PowerOn);
+        for frame in 0..2 {
+            assert!(frame_input(&mut cpu, frame, Some(&mut host), None, Some(&mut movie), 0));
+        }
+        assert_eq!(movie.frames[0].joypads[0], JoypadButton::BUTTON_A);
+        assert_eq!(movie.frames[1].joypads[0], JoypadButton::START);
+
+        // Played back under a script that presses something else, the movie wins
+        let mut replay = CPU::new();
+        let mut host = ScriptHost::from_source("emu::on_frame(|| joypad::set(1, joypad::B));", &mut replay).unwrap();
+        movie.begin(&mut replay).unwrap();
+        assert!(frame_input(&mut replay, 0, Some(&mut host), Some(&movie), None, 0));
+        assert_eq!(replay.joypad1.buttons(), JoypadButton::BUTTON_A);
+        assert!(frame_input(&mut replay, 1, Some(&mut host), Some(&movie), None, 0));
+        assert_eq!(replay.joypad1.buttons(), JoypadButton::START);
+        assert!(!frame_input(&mut replay, 2, Some(&mut host), Some(&movie), None, 0));
+    }
+}
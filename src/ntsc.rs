@@ -0,0 +1,219 @@
+use std::f32::consts::PI;
+
+use crate::palette::{composite_level, output_pixel, yiq_to_rgb, NtscParams, Palette};
+
+// Every PPU dot lasts 8 samples of the 12 phase colour subcarrier
+const SAMPLES_PER_PIXEL: usize = 8;
+const SUBCARRIER_PHASES: usize = 12;
+// One output pixel every 3 samples makes the picture 8/3 as wide as the PPU's
+const SAMPLES_PER_OUTPUT: usize = 3;
+// A scanline is 341 dots long, which leaves the subcarrier 4 phases further along
+const LINE_PHASE_STEP: usize = 4;
+const PIXEL_VALUES: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NtscPreset {
+    // Luma and chroma share one wire, chroma bleeds into luma as artifact colours and dot crawl
+    Composite,
+    // Separate luma and chroma wires, colour is still band limited
+    SVideo,
+    // Straight palette lookup, only widened to the same output size
+    Rgb,
+}
+
+impl NtscPreset {
+    pub fn from_name(name: &str) -> Option<NtscPreset> {
+        match name {
+            "composite" => Some(NtscPreset::Composite),
+            "svideo" | "s-video" => Some(NtscPreset::SVideo),
+            "rgb" => Some(NtscPreset::Rgb),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscFilterParams {
+    pub preset: NtscPreset,
+    pub signal: NtscParams,
+    // Samples averaged into luma, 12 removes all chroma and fewer lets it through as dots
+    pub luma_width: usize,
+    // Samples averaged into chroma, wider windows blur colour edges
+    pub chroma_width: usize,
+    // Shift the subcarrier every frame like the PPU does, making artifacts crawl
+    pub dot_crawl: bool,
+}
+
+impl NtscFilterParams {
+    pub fn preset(preset: NtscPreset) -> Self {
+        let (luma_width, chroma_width, dot_crawl) = match preset {
+            NtscPreset::Composite => (8, 12, true),
+            NtscPreset::SVideo => (4, 12, false),
+            NtscPreset::Rgb => (1, 1, false),
+        };
+        NtscFilterParams { preset, signal: NtscParams::default(), luma_width, chroma_width, dot_crawl }
+    }
+}
+
+// Software NTSC encoder/decoder over the PPU's output, pixels as built by
+// `palette::pixel`. The RGB preset looks colours up in the given palette.
+pub struct NtscFilter {
+    params: NtscFilterParams,
+    palette: Palette,
+    frame: usize,
+    // Signal level of each pixel value at each subcarrier phase, contrast and brightness applied
+    levels: Vec<[f32; SUBCARRIER_PHASES]>,
+    // Average level of each pixel value, what an S-Video luma wire carries
+    lumas: Vec<f32>,
+    cos: [f32; SUBCARRIER_PHASES],
+    sin: [f32; SUBCARRIER_PHASES],
+}
+
+impl NtscFilter {
+    pub fn new(params: NtscFilterParams, palette: &Palette) -> Self {
+        let signal = params.signal;
+        let levels: Vec<[f32; SUBCARRIER_PHASES]> = (0..PIXEL_VALUES)
+            .map(|pixel| {
+                let mut levels = [0.0; SUBCARRIER_PHASES];
+                for (phase, level) in levels.iter_mut().enumerate() {
+                    let v = composite_level(pixel as u16, phase as u8);
+                    *level = ((v - 0.5) * signal.contrast + 0.5) * signal.brightness;
+                }
+                levels
+            })
+            .collect();
+        let lumas = levels.iter().map(|levels| levels.iter().sum::<f32>() / SUBCARRIER_PHASES as f32).collect();
+
+        let mut cos = [0.0; SUBCARRIER_PHASES];
+        let mut sin = [0.0; SUBCARRIER_PHASES];
+        for phase in 0..SUBCARRIER_PHASES {
+            let angle = PI / 6.0 * (phase as f32 + signal.hue / 30.0);
+            cos[phase] = angle.cos();
+            sin[phase] = angle.sin();
+        }
+
+        NtscFilter { params, palette: palette.clone(), frame: 0, levels, lumas, cos, sin }
+    }
+
+    pub fn params(&self) -> &NtscFilterParams {
+        &self.params
+    }
+
+    pub fn output_width(width: usize) -> usize {
+        width * SAMPLES_PER_PIXEL / SAMPLES_PER_OUTPUT
+    }
+
+    // Filters one frame into RGB24 of output_width(width) by the same height
+    pub fn apply(&mut self, pixels: &[u16], width: usize) -> Vec<u8> {
+        let out_width = NtscFilter::output_width(width);
+        let height = pixels.len().checked_div(width).unwrap_or(0);
+        let mut out = Vec::with_capacity(out_width * height * 3);
+
+        let frame_phase = if self.params.dot_crawl { self.frame * LINE_PHASE_STEP } else { 0 };
+        for (row, line) in pixels.chunks_exact(width.max(1)).take(height).enumerate() {
+            let phase = (frame_phase + row * LINE_PHASE_STEP) % SUBCARRIER_PHASES;
+            match self.params.preset {
+                NtscPreset::Rgb => self.rgb_line(line, out_width, &mut out),
+                _ => self.decode_line(line, phase, out_width, &mut out),
+            }
+        }
+
+        self.frame = self.frame.wrapping_add(1);
+        out
+    }
+
+    fn rgb_line(&self, line: &[u16], out_width: usize, out: &mut Vec<u8>) {
+        for x in 0..out_width {
+            let pixel = line[x * SAMPLES_PER_OUTPUT / SAMPLES_PER_PIXEL];
+            let (r, g, b) = self.palette.pixel_color(pixel);
+            out.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    fn decode_line(&self, line: &[u16], phase: usize, out_width: usize, out: &mut Vec<u8>) {
+        let signal: Vec<f32> = (0..line.len() * SAMPLES_PER_PIXEL)
+            .map(|s| self.levels[output_pixel(line[s / SAMPLES_PER_PIXEL]) as usize][(phase + s) % SUBCARRIER_PHASES])
+            .collect();
+        let luma: Vec<f32> = match self.params.preset {
+            NtscPreset::SVideo => (0..signal.len())
+                .map(|s| self.lumas[output_pixel(line[s / SAMPLES_PER_PIXEL]) as usize])
+                .collect(),
+            _ => Vec::new(),
+        };
+        let luma = if luma.is_empty() { &signal } else { &luma };
+
+        for x in 0..out_width {
+            let center = x * SAMPLES_PER_OUTPUT + SAMPLES_PER_OUTPUT / 2;
+
+            let luma_window = window(center, self.params.luma_width, signal.len());
+            let y = luma_window.clone().map(|s| luma[s]).sum::<f32>() / luma_window.len().max(1) as f32;
+
+            let (mut i, mut q, mut count) = (0.0, 0.0, 0);
+            for s in window(center, self.params.chroma_width, signal.len()) {
+                let phase = (phase + s) % SUBCARRIER_PHASES;
+                i += signal[s] * self.cos[phase];
+                q += signal[s] * self.sin[phase];
+                count += 1;
+            }
+            let scale = self.params.signal.saturation / count.max(1) as f32;
+
+            let (r, g, b) = yiq_to_rgb(y, i * scale, q * scale, self.params.signal.gamma);
+            out.extend_from_slice(&[r, g, b]);
+        }
+    }
+}
+
+// Sample indices of a window centred on `center`, clamped to the line
+fn window(center: usize, width: usize, len: usize) -> std::ops::Range<usize> {
+    let start = center.saturating_sub(width / 2);
+    let end = (start + width).min(len);
+    start..end
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::palette::pixel;
+
+    fn close((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> bool {
+        (r1 as i16 - r2 as i16).abs() < 12 && (g1 as i16 - g2 as i16).abs() < 12 && (b1 as i16 - b2 as i16).abs() < 12
+    }
+
+    #[test]
+    fn test_flat_colour_matches_palette() {
+        let palette = Palette::default();
+        let pixels = vec![0x16_u16; 16 * 2];
+
+        for preset in [NtscPreset::Composite, NtscPreset::SVideo, NtscPreset::Rgb] {
+            // A full subcarrier period of luma cancels the composite dot pattern
+            let params = NtscFilterParams { luma_width: 12, ..NtscFilterParams::preset(preset) };
+            let mut filter = NtscFilter::new(params, &palette);
+            let out = filter.apply(&pixels, 16);
+            assert_eq!(out.len(), NtscFilter::output_width(16) * 2 * 3);
+
+            // Away from the edges a flat area decodes back to the palette colour
+            let middle = 20 * 3;
+            let rgb = (out[middle], out[middle + 1], out[middle + 2]);
+            assert!(close(rgb, palette.color(0x16, 0)), "{:?} gave {:?}", preset, rgb);
+        }
+
+        // The RGB preset takes colours from the palette it is given, greyscale applied
+        let mut data = vec![0; 192];
+        data[0x10 * 3..0x10 * 3 + 3].copy_from_slice(&[1, 2, 3]);
+        let mut filter = NtscFilter::new(NtscFilterParams::preset(NtscPreset::Rgb), &Palette::from_pal(&data).unwrap());
+        assert_eq!(filter.apply(&[pixel(0x16, 0b0000_0001); 16], 16)[..3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_composite_artifacts_crawl() {
+        // Alternating black and white columns produce colour fringes that move every frame
+        let pixels: Vec<u16> = (0..32).map(|x| if x % 2 == 0 { 0x0F } else { 0x30 }).collect();
+        let mut filter = NtscFilter::new(NtscFilterParams::preset(NtscPreset::Composite), &Palette::default());
+        let first = filter.apply(&pixels, 32);
+        let second = filter.apply(&pixels, 32);
+        assert_ne!(first, second);
+
+        let mut filter = NtscFilter::new(NtscFilterParams::preset(NtscPreset::Rgb), &Palette::default());
+        assert_eq!(filter.apply(&pixels, 32), filter.apply(&pixels, 32));
+    }
+}
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct AccessKind: u8 {
+        const READ      = 0b001;
+        const WRITE     = 0b010;
+        // Opcode fetches, reported instead of a read
+        const EXECUTE   = 0b100;
+    }
+}
+
+// One bus access made by the CPU. `cycle` is the cycle count at the start of the
+// instruction and `pc` the address of its opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub value: u8,
+    pub cycle: u64,
+    pub pc: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObserverId(u64);
+
+struct Observer {
+    id: ObserverId,
+    kinds: AccessKind,
+    range: RangeInclusive<u16>,
+    callback: Box<dyn FnMut(&MemAccess)>,
+}
+
+// Callbacks watching CPU reads, writes and opcode fetches on address ranges.
+// The CPU checks `watches` before building an access, so memory accesses cost a
+// single flag test while nothing watches that kind.
+#[derive(Default)]
+pub struct MemObservers {
+    observers: RefCell<Vec<Observer>>,
+    kinds: AccessKind,
+    next_id: u64,
+}
+
+impl MemObservers {
+    pub fn new() -> Self {
+        MemObservers::default()
+    }
+
+    pub fn add<F>(&mut self, kinds: AccessKind, range: RangeInclusive<u16>, callback: F) -> ObserverId
+    where F: FnMut(&MemAccess) + 'static {
+        let id = ObserverId(self.next_id);
+        self.next_id += 1;
+        self.observers.get_mut().push(Observer { id, kinds, range, callback: Box::new(callback) });
+        self.kinds |= kinds;
+        id
+    }
+
+    pub fn remove(&mut self, id: ObserverId) -> bool {
+        let observers = self.observers.get_mut();
+        let len = observers.len();
+        observers.retain(|observer| observer.id != id);
+        self.kinds = observers.iter().fold(AccessKind::empty(), |kinds, observer| kinds | observer.kinds);
+        observers.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.observers.get_mut().clear();
+        self.kinds = AccessKind::empty();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    #[inline]
+    pub fn watches(&self, kind: AccessKind) -> bool {
+        self.kinds.intersects(kind)
+    }
+
+    pub(crate) fn notify(&self, access: &MemAccess) {
+        for observer in self.observers.borrow_mut().iter_mut() {
+            if observer.kinds.intersects(access.kind) && observer.range.contains(&access.addr) {
+                (observer.callback)(access);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_observers_filter_by_kind_and_range() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut observers = MemObservers::new();
+        assert!(!observers.watches(AccessKind::READ));
+
+        let log = seen.clone();
+        let id = observers.add(AccessKind::WRITE, 0x10..=0x1F, move |access| log.borrow_mut().push(access.addr));
+        assert!(observers.watches(AccessKind::WRITE));
+        assert!(!observers.watches(AccessKind::READ | AccessKind::EXECUTE));
+
+        for addr in [0x0F, 0x10, 0x1F, 0x20] {
+            observers.notify(&MemAccess { kind: AccessKind::WRITE, addr, value: 0, cycle: 0, pc: 0 });
+        }
+        observers.notify(&MemAccess { kind: AccessKind::READ, addr: 0x15, value: 0, cycle: 0, pc: 0 });
+        assert_eq!(*seen.borrow(), vec![0x10, 0x1F]);
+
+        assert!(observers.remove(id));
+        assert!(!observers.remove(id));
+        assert!(observers.is_empty());
+    }
+}
@@ -1,14 +1,19 @@
-use std::collections::HashMap;
 use once_cell::sync::Lazy;
 
-use crate::cpu::AddressingMode;
+use crate::cpu::{AddressingMode, CPUFlags, CpuError, CPU};
 
+// Every instruction is executed through a handler taking the decoded addressing mode.
+// Operand fetching is left to the handler so implied instructions can ignore it.
+pub type Handler = fn(&mut CPU, &AddressingMode) -> Result<(), CpuError>;
+
+#[derive(Debug, Clone, Copy)]
 pub struct OpCode {
     pub code: u8,
     pub mnemonic: &'static str,
     pub len: u8,
     pub cycles: u8,
     pub mode: AddressingMode,
+    pub handler: Handler,
 }
 
 impl OpCode {
@@ -18,6 +23,7 @@ impl OpCode {
         len: u8,
         cycles: u8,
         mode: AddressingMode,
+        handler: Handler,
     ) -> Self {
         OpCode {
             code,
@@ -25,212 +31,237 @@ impl OpCode {
             len,
             cycles,
             mode,
+            handler,
         }
     }
+
+    // Placeholder occupying the table slots of bytes that have no instruction
+    fn unknown(code: u8) -> Self {
+        OpCode::new(code, "???", 1, 2, AddressingMode::NoneAddressing, CPU::unknown_opcode)
+    }
 }
 
 pub static CPU_OPS_CODES: Lazy<Vec<OpCode>> = Lazy::new(|| vec![
     // Loads
-    OpCode::new(0xA9, "LDA", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xA5, "LDA", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xB5, "LDA", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0xAD, "LDA", 3, 7, AddressingMode::Absolute),
-    OpCode::new(0xBD, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0xB9, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0xA1, "LDA", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0xB1, "LDA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-    OpCode::new(0xA2, "LDX", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xA6, "LDX", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xB6, "LDX", 2, 4, AddressingMode::ZeroPage_Y),
-    OpCode::new(0xAE, "LDX", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0xBE, "LDX", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-
-    OpCode::new(0xA0, "LDY", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xA4, "LDY", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xB4, "LDY", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0xAC, "LDY", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0xBC, "LDY", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+    OpCode::new(0xA9, "LDA", 2, 2, AddressingMode::Immediate, CPU::lda),
+    OpCode::new(0xA5, "LDA", 2, 3, AddressingMode::ZeroPage, CPU::lda),
+    OpCode::new(0xB5, "LDA", 2, 4, AddressingMode::ZeroPage_X, CPU::lda),
+    OpCode::new(0xAD, "LDA", 3, 4, AddressingMode::Absolute, CPU::lda),
+    OpCode::new(0xBD, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::lda),
+    OpCode::new(0xB9, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::lda),
+    OpCode::new(0xA1, "LDA", 2, 6, AddressingMode::Indirect_X, CPU::lda),
+    OpCode::new(0xB1, "LDA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, CPU::lda),
+
+    OpCode::new(0xA2, "LDX", 2, 2, AddressingMode::Immediate, CPU::ldx),
+    OpCode::new(0xA6, "LDX", 2, 3, AddressingMode::ZeroPage, CPU::ldx),
+    OpCode::new(0xB6, "LDX", 2, 4, AddressingMode::ZeroPage_Y, CPU::ldx),
+    OpCode::new(0xAE, "LDX", 3, 4, AddressingMode::Absolute, CPU::ldx),
+    OpCode::new(0xBE, "LDX", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::ldx),
+
+    OpCode::new(0xA0, "LDY", 2, 2, AddressingMode::Immediate, CPU::ldy),
+    OpCode::new(0xA4, "LDY", 2, 3, AddressingMode::ZeroPage, CPU::ldy),
+    OpCode::new(0xB4, "LDY", 2, 4, AddressingMode::ZeroPage_X, CPU::ldy),
+    OpCode::new(0xAC, "LDY", 3, 4, AddressingMode::Absolute, CPU::ldy),
+    OpCode::new(0xBC, "LDY", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::ldy),
 
     // Stores
-    OpCode::new(0x85, "STA", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x95, "STA", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0x8D, "STA", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0x9D, "STA", 3, 5, AddressingMode::Absolute_X),
-    OpCode::new(0x99, "STA", 3, 5, AddressingMode::Absolute_Y),
-    OpCode::new(0x81, "STA", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0x91, "STA", 2, 6, AddressingMode::Indirect_Y),
-
-    OpCode::new(0x86, "STX", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x96, "STX", 2, 4, AddressingMode::ZeroPage_Y),
-    OpCode::new(0x8E, "STX", 3, 4, AddressingMode::Absolute),
-
-    OpCode::new(0x84, "STY", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x94, "STY", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0x8C, "STY", 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x85, "STA", 2, 3, AddressingMode::ZeroPage, CPU::sta),
+    OpCode::new(0x95, "STA", 2, 4, AddressingMode::ZeroPage_X, CPU::sta),
+    OpCode::new(0x8D, "STA", 3, 4, AddressingMode::Absolute, CPU::sta),
+    OpCode::new(0x9D, "STA", 3, 5, AddressingMode::Absolute_X, CPU::sta),
+    OpCode::new(0x99, "STA", 3, 5, AddressingMode::Absolute_Y, CPU::sta),
+    OpCode::new(0x81, "STA", 2, 6, AddressingMode::Indirect_X, CPU::sta),
+    OpCode::new(0x91, "STA", 2, 6, AddressingMode::Indirect_Y, CPU::sta),
+
+    OpCode::new(0x86, "STX", 2, 3, AddressingMode::ZeroPage, CPU::stx),
+    OpCode::new(0x96, "STX", 2, 4, AddressingMode::ZeroPage_Y, CPU::stx),
+    OpCode::new(0x8E, "STX", 3, 4, AddressingMode::Absolute, CPU::stx),
+
+    OpCode::new(0x84, "STY", 2, 3, AddressingMode::ZeroPage, CPU::sty),
+    OpCode::new(0x94, "STY", 2, 4, AddressingMode::ZeroPage_X, CPU::sty),
+    OpCode::new(0x8C, "STY", 3, 4, AddressingMode::Absolute, CPU::sty),
 
     // Arithmetic
-    OpCode::new(0x69, "ADC", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0x65, "ADC", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x75, "ADC", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0x6D, "ADC", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0x7D, "ADC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0x79, "ADC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0x61, "ADC", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0x71, "ADC", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-    OpCode::new(0xE9, "SBC", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xE5, "SBC", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xF5, "SBC", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0xED, "SBC", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0xFD, "SBC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0xF9, "SBC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0xE1, "SBC", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0xF1, "SBC", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+    OpCode::new(0x69, "ADC", 2, 2, AddressingMode::Immediate, CPU::adc),
+    OpCode::new(0x65, "ADC", 2, 3, AddressingMode::ZeroPage, CPU::adc),
+    OpCode::new(0x75, "ADC", 2, 4, AddressingMode::ZeroPage_X, CPU::adc),
+    OpCode::new(0x6D, "ADC", 3, 4, AddressingMode::Absolute, CPU::adc),
+    OpCode::new(0x7D, "ADC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::adc),
+    OpCode::new(0x79, "ADC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::adc),
+    OpCode::new(0x61, "ADC", 2, 6, AddressingMode::Indirect_X, CPU::adc),
+    OpCode::new(0x71, "ADC", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, CPU::adc),
+
+    OpCode::new(0xE9, "SBC", 2, 2, AddressingMode::Immediate, CPU::sbc),
+    OpCode::new(0xE5, "SBC", 2, 3, AddressingMode::ZeroPage, CPU::sbc),
+    OpCode::new(0xF5, "SBC", 2, 4, AddressingMode::ZeroPage_X, CPU::sbc),
+    OpCode::new(0xED, "SBC", 3, 4, AddressingMode::Absolute, CPU::sbc),
+    OpCode::new(0xFD, "SBC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::sbc),
+    OpCode::new(0xF9, "SBC", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::sbc),
+    OpCode::new(0xE1, "SBC", 2, 6, AddressingMode::Indirect_X, CPU::sbc),
+    OpCode::new(0xF1, "SBC", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, CPU::sbc),
 
     // Logical
-    OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0x2D, "AND", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0x3D, "AND", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0x39, "AND", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0x31, "AND", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-    OpCode::new(0x49, "EOR", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0x45, "EOR", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x55, "EOR", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0x4D, "EOR", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0x5D, "EOR", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0x59, "EOR", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0x41, "EOR", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0x51, "EOR", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-    OpCode::new(0x09, "ORA", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0x05, "ORA", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0x15, "ORA", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0x0D, "ORA", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0x1D, "ORA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0x19, "ORA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0x01, "ORA", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0x11, "ORA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+    OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate, CPU::and),
+    OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage, CPU::and),
+    OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X, CPU::and),
+    OpCode::new(0x2D, "AND", 3, 4, AddressingMode::Absolute, CPU::and),
+    OpCode::new(0x3D, "AND", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::and),
+    OpCode::new(0x39, "AND", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::and),
+    OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X, CPU::and),
+    OpCode::new(0x31, "AND", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, CPU::and),
+
+    OpCode::new(0x49, "EOR", 2, 2, AddressingMode::Immediate, CPU::eor),
+    OpCode::new(0x45, "EOR", 2, 3, AddressingMode::ZeroPage, CPU::eor),
+    OpCode::new(0x55, "EOR", 2, 4, AddressingMode::ZeroPage_X, CPU::eor),
+    OpCode::new(0x4D, "EOR", 3, 4, AddressingMode::Absolute, CPU::eor),
+    OpCode::new(0x5D, "EOR", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::eor),
+    OpCode::new(0x59, "EOR", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::eor),
+    OpCode::new(0x41, "EOR", 2, 6, AddressingMode::Indirect_X, CPU::eor),
+    OpCode::new(0x51, "EOR", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, CPU::eor),
+
+    OpCode::new(0x09, "ORA", 2, 2, AddressingMode::Immediate, CPU::ora),
+    OpCode::new(0x05, "ORA", 2, 3, AddressingMode::ZeroPage, CPU::ora),
+    OpCode::new(0x15, "ORA", 2, 4, AddressingMode::ZeroPage_X, CPU::ora),
+    OpCode::new(0x0D, "ORA", 3, 4, AddressingMode::Absolute, CPU::ora),
+    OpCode::new(0x1D, "ORA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, CPU::ora),
+    OpCode::new(0x19, "ORA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, CPU::ora),
+    OpCode::new(0x01, "ORA", 2, 6, AddressingMode::Indirect_X, CPU::ora),
+    OpCode::new(0x11, "ORA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, CPU::ora),
 
     // Shifts
-    OpCode::new(0x0A, "ASL", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
-    OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X),
-    OpCode::new(0x0E, "ASL", 3, 6, AddressingMode::Absolute),
-    OpCode::new(0x1E, "ASL", 3, 7, AddressingMode::Absolute_X),
-
-    OpCode::new(0x4A, "LSR", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
-    OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X),
-    OpCode::new(0x4E, "LSR", 3, 6, AddressingMode::Absolute),
-    OpCode::new(0x5E, "LSR", 3, 7, AddressingMode::Absolute_X),
-
-    OpCode::new(0x2A, "ROL", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
-    OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X),
-    OpCode::new(0x2E, "ROL", 3, 6, AddressingMode::Absolute),
-    OpCode::new(0x3E, "ROL", 3, 7, AddressingMode::Absolute_X),
-
-    OpCode::new(0x6A, "ROR", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
-    OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X),
-    OpCode::new(0x6E, "ROR", 3, 6, AddressingMode::Absolute),
-    OpCode::new(0x7E, "ROR", 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0x0A, "ASL", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.asl_accumulator(); Ok(()) }),
+    OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage, |cpu, mode| cpu.asl(mode).map(|_| ())),
+    OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X, |cpu, mode| cpu.asl(mode).map(|_| ())),
+    OpCode::new(0x0E, "ASL", 3, 6, AddressingMode::Absolute, |cpu, mode| cpu.asl(mode).map(|_| ())),
+    OpCode::new(0x1E, "ASL", 3, 7, AddressingMode::Absolute_X, |cpu, mode| cpu.asl(mode).map(|_| ())),
+
+    OpCode::new(0x4A, "LSR", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.lsr_accumulator(); Ok(()) }),
+    OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage, |cpu, mode| cpu.lsr(mode).map(|_| ())),
+    OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X, |cpu, mode| cpu.lsr(mode).map(|_| ())),
+    OpCode::new(0x4E, "LSR", 3, 6, AddressingMode::Absolute, |cpu, mode| cpu.lsr(mode).map(|_| ())),
+    OpCode::new(0x5E, "LSR", 3, 7, AddressingMode::Absolute_X, |cpu, mode| cpu.lsr(mode).map(|_| ())),
+
+    OpCode::new(0x2A, "ROL", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.rol_accumulator(); Ok(()) }),
+    OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage, |cpu, mode| cpu.rol(mode).map(|_| ())),
+    OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X, |cpu, mode| cpu.rol(mode).map(|_| ())),
+    OpCode::new(0x2E, "ROL", 3, 6, AddressingMode::Absolute, |cpu, mode| cpu.rol(mode).map(|_| ())),
+    OpCode::new(0x3E, "ROL", 3, 7, AddressingMode::Absolute_X, |cpu, mode| cpu.rol(mode).map(|_| ())),
+
+    OpCode::new(0x6A, "ROR", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.ror_accumulator(); Ok(()) }),
+    OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage, |cpu, mode| cpu.ror(mode).map(|_| ())),
+    OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X, |cpu, mode| cpu.ror(mode).map(|_| ())),
+    OpCode::new(0x6E, "ROR", 3, 6, AddressingMode::Absolute, |cpu, mode| cpu.ror(mode).map(|_| ())),
+    OpCode::new(0x7E, "ROR", 3, 7, AddressingMode::Absolute_X, |cpu, mode| cpu.ror(mode).map(|_| ())),
 
     // Increments & Decrements
-    OpCode::new(0xC6, "DEC", 2, 5, AddressingMode::ZeroPage),
-    OpCode::new(0xD6, "DEC", 2, 6, AddressingMode::ZeroPage_X),
-    OpCode::new(0xCE, "DEC", 3, 6, AddressingMode::Absolute),
-    OpCode::new(0xDE, "DEC", 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0xC6, "DEC", 2, 5, AddressingMode::ZeroPage, |cpu, mode| cpu.dec(mode).map(|_| ())),
+    OpCode::new(0xD6, "DEC", 2, 6, AddressingMode::ZeroPage_X, |cpu, mode| cpu.dec(mode).map(|_| ())),
+    OpCode::new(0xCE, "DEC", 3, 6, AddressingMode::Absolute, |cpu, mode| cpu.dec(mode).map(|_| ())),
+    OpCode::new(0xDE, "DEC", 3, 7, AddressingMode::Absolute_X, |cpu, mode| cpu.dec(mode).map(|_| ())),
 
-    OpCode::new(0xE6, "INC", 2, 5, AddressingMode::ZeroPage),
-    OpCode::new(0xF6, "INC", 2, 6, AddressingMode::ZeroPage_X),
-    OpCode::new(0xEE, "INC", 3, 6, AddressingMode::Absolute),
-    OpCode::new(0xFE, "INC", 3, 7, AddressingMode::Absolute_X),
+    OpCode::new(0xE6, "INC", 2, 5, AddressingMode::ZeroPage, |cpu, mode| cpu.inc(mode).map(|_| ())),
+    OpCode::new(0xF6, "INC", 2, 6, AddressingMode::ZeroPage_X, |cpu, mode| cpu.inc(mode).map(|_| ())),
+    OpCode::new(0xEE, "INC", 3, 6, AddressingMode::Absolute, |cpu, mode| cpu.inc(mode).map(|_| ())),
+    OpCode::new(0xFE, "INC", 3, 7, AddressingMode::Absolute_X, |cpu, mode| cpu.inc(mode).map(|_| ())),
 
-    OpCode::new(0xE8, "INX", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xC8, "INY", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xE8, "INX", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.inx(); Ok(()) }),
+    OpCode::new(0xC8, "INY", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.iny(); Ok(()) }),
 
-    OpCode::new(0xCA, "DEX", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x88, "DEY", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xCA, "DEX", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.dex(); Ok(()) }),
+    OpCode::new(0x88, "DEY", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.dey(); Ok(()) }),
 
     // Comparisions
-    OpCode::new(0xC9, "CMP", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xC5, "CMP", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xD5, "CMP", 2, 4, AddressingMode::ZeroPage_X),
-    OpCode::new(0xCD, "CMP", 3, 4, AddressingMode::Absolute),
-    OpCode::new(0xDD, "CMP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    OpCode::new(0xD9, "CMP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    OpCode::new(0xC1, "CMP", 2, 6, AddressingMode::Indirect_X),
-    OpCode::new(0xD1, "CMP", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-    OpCode::new(0xE0, "CPX", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xE4, "CPX", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xEC, "CPX", 3, 4, AddressingMode::Absolute),
-
-    OpCode::new(0xC0, "CPY", 2, 2, AddressingMode::Immediate),
-    OpCode::new(0xC4, "CPY", 2, 3, AddressingMode::ZeroPage),
-    OpCode::new(0xCC, "CPY", 3, 4, AddressingMode::Absolute),
+    OpCode::new(0xC9, "CMP", 2, 2, AddressingMode::Immediate, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xC5, "CMP", 2, 3, AddressingMode::ZeroPage, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xD5, "CMP", 2, 4, AddressingMode::ZeroPage_X, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xCD, "CMP", 3, 4, AddressingMode::Absolute, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xDD, "CMP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xD9, "CMP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xC1, "CMP", 2, 6, AddressingMode::Indirect_X, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+    OpCode::new(0xD1, "CMP", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y, |cpu, mode| cpu.compare(mode, cpu.register_a)),
+
+    OpCode::new(0xE0, "CPX", 2, 2, AddressingMode::Immediate, |cpu, mode| cpu.compare(mode, cpu.register_x)),
+    OpCode::new(0xE4, "CPX", 2, 3, AddressingMode::ZeroPage, |cpu, mode| cpu.compare(mode, cpu.register_x)),
+    OpCode::new(0xEC, "CPX", 3, 4, AddressingMode::Absolute, |cpu, mode| cpu.compare(mode, cpu.register_x)),
+
+    OpCode::new(0xC0, "CPY", 2, 2, AddressingMode::Immediate, |cpu, mode| cpu.compare(mode, cpu.register_y)),
+    OpCode::new(0xC4, "CPY", 2, 3, AddressingMode::ZeroPage, |cpu, mode| cpu.compare(mode, cpu.register_y)),
+    OpCode::new(0xCC, "CPY", 3, 4, AddressingMode::Absolute, |cpu, mode| cpu.compare(mode, cpu.register_y)),
 
     // Branches
-    OpCode::new(0x90, "BCC", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0xB0, "BCS", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0xF0, "BEQ", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0x30, "BMI", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0xD0, "BNE", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0x10, "BPL", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0x50, "BVC", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-    OpCode::new(0x70, "BVS", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing),
-
-    OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage),   
-    OpCode::new(0x2C, "BIT", 3, 4, AddressingMode::Absolute),
+    OpCode::new(0x90, "BCC", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(!cpu.status.contains(CPUFlags::CARRY)); Ok(()) }),
+    OpCode::new(0xB0, "BCS", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(cpu.status.contains(CPUFlags::CARRY)); Ok(()) }),
+    OpCode::new(0xF0, "BEQ", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(cpu.status.contains(CPUFlags::ZERO)); Ok(()) }),
+    OpCode::new(0x30, "BMI", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(cpu.status.contains(CPUFlags::NEGATIVE)); Ok(()) }),
+    OpCode::new(0xD0, "BNE", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(!cpu.status.contains(CPUFlags::ZERO)); Ok(()) }),
+    OpCode::new(0x10, "BPL", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(!cpu.status.contains(CPUFlags::NEGATIVE)); Ok(()) }),
+    OpCode::new(0x50, "BVC", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(!cpu.status.contains(CPUFlags::OVERFLOW)); Ok(()) }),
+    OpCode::new(0x70, "BVS", 2, 2 /*+1 if branch succeeds +2 if to a new page*/, AddressingMode::NoneAddressing, |cpu, _| { cpu.branch(cpu.status.contains(CPUFlags::OVERFLOW)); Ok(()) }),
+
+    OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage, CPU::bit),   
+    OpCode::new(0x2C, "BIT", 3, 4, AddressingMode::Absolute, CPU::bit),
 
     // Jumps & Calls
-    OpCode::new(0x4C, "JMP", 3, 3, AddressingMode::NoneAddressing), // AddressingMode that acts as Immediate
-    OpCode::new(0x6C, "JMP", 3, 5, AddressingMode::NoneAddressing), // AddressingMode:Indirect with 6502 bug
-    OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing),
-    OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing),
+    OpCode::new(0x4C, "JMP", 3, 3, AddressingMode::NoneAddressing, |cpu, _| { cpu.jmp_absolute(); Ok(()) }), // AddressingMode that acts as Immediate
+    OpCode::new(0x6C, "JMP", 3, 5, AddressingMode::NoneAddressing, |cpu, _| { cpu.jmp_indirect(); Ok(()) }), // AddressingMode:Indirect with 6502 bug
+    OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing, |cpu, _| { cpu.jsr(); Ok(()) }),
+    OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing, |cpu, _| { cpu.rts(); Ok(()) }),
+    OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing, |cpu, _| { cpu.rti(); Ok(()) }),
 
     // Flags clear
-    OpCode::new(0x18, "CLC", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xD8, "CLD", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x58, "CLI", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xB8, "CLV", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x18, "CLC", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.clear_carry_flag(); Ok(()) }),
+    OpCode::new(0xD8, "CLD", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.status.remove(CPUFlags::DECIMAL_MODE); Ok(()) }),
+    OpCode::new(0x58, "CLI", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.status.remove(CPUFlags::INTERRUPT_DISABLE); Ok(()) }),
+    OpCode::new(0xB8, "CLV", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.status.remove(CPUFlags::OVERFLOW); Ok(()) }),
 
     // Flags set
-    OpCode::new(0x38, "SEC", 1, 2, AddressingMode::NoneAddressing), 
-    OpCode::new(0x78, "SEI", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xF8, "SED", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0x38, "SEC", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.set_carry_flag(); Ok(()) }), 
+    OpCode::new(0x78, "SEI", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.status.insert(CPUFlags::INTERRUPT_DISABLE); Ok(()) }),
+    OpCode::new(0xF8, "SED", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.status.insert(CPUFlags::DECIMAL_MODE); Ok(()) }),
     
     // Stack
-    OpCode::new(0x48, "PHA", 1, 3, AddressingMode::NoneAddressing),
-    OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing),
-    OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing),
-    OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
+    OpCode::new(0x48, "PHA", 1, 3, AddressingMode::NoneAddressing, |cpu, _| { cpu.stack_push(cpu.register_a); Ok(()) }),
+    OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing, |cpu, _| { cpu.php(); Ok(()) }),
+    OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing, |cpu, _| { cpu.pla(); Ok(()) }),
+    OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing, |cpu, _| { cpu.plp(); Ok(()) }),
 
     
-    OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xa8, "TAY", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0xba, "TSX", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x8a, "TXA", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x9a, "TXS", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x98, "TYA", 1, 2, AddressingMode::NoneAddressing),
+    OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.tax(); Ok(()) }),
+    OpCode::new(0xa8, "TAY", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.tay(); Ok(()) }),
+    OpCode::new(0xba, "TSX", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.tsx(); Ok(()) }),
+    OpCode::new(0x8a, "TXA", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.txa(); Ok(()) }),
+    OpCode::new(0x9a, "TXS", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.txs(); Ok(()) }),
+    OpCode::new(0x98, "TYA", 1, 2, AddressingMode::NoneAddressing, |cpu, _| { cpu.tya(); Ok(()) }),
 
     // Miscellaneous
-    OpCode::new(0xEA, "NOP", 1, 2, AddressingMode::NoneAddressing),
-    OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
+    OpCode::new(0xEA, "NOP", 1, 2, AddressingMode::NoneAddressing, |_, _| Ok(())),
+    OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing, |_, _| Ok(())),
+
+    // Unofficial opcodes that lock up the CPU
+    OpCode::new(0x02, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x12, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x22, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x32, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x42, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x52, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x62, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x72, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0x92, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0xB2, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0xD2, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
+    OpCode::new(0xF2, "JAM", 1, 2, AddressingMode::NoneAddressing, CPU::jam),
     
     
 ]);
 
-pub static OPCODES_MAP: Lazy<HashMap<u8, &'static OpCode>> = Lazy::new(|| {
-    let mut map = HashMap::new();
+// Decode table indexed directly by the opcode byte, built once from CPU_OPS_CODES
+pub static OPCODE_TABLE: Lazy<[OpCode; 256]> = Lazy::new(|| {
+    let mut table = [OpCode::unknown(0); 256];
+    for (code, op) in table.iter_mut().enumerate() {
+        *op = OpCode::unknown(code as u8);
+    }
     for op in CPU_OPS_CODES.iter() {
-        map.insert(op.code, op);
+        table[op.code as usize] = *op;
     }
-    map
-});
\ No newline at end of file
+    table
+});
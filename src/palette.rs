@@ -0,0 +1,258 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+
+pub const PALETTE_SIZE: usize = 64;
+const EMPHASIS_VARIANTS: usize = 8;
+
+// PPUMASK bits applied at output time
+const MASK_GREYSCALE: u8 = 0b0000_0001;
+const MASK_EMPHASIS_SHIFT: u8 = 5;
+// Where PPUMASK greyscale goes in a PPU style pixel, above the emphasis bits
+const PIXEL_GREYSCALE: u16 = 1 << 9;
+
+// Composite output voltages of the 2C02 for luma levels 0-3, low then high phase
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+pub type Rgb = (u8, u8, u8);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscParams {
+    // Hue rotation in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams { hue: 0.0, saturation: 1.0, contrast: 1.0, brightness: 1.0, gamma: 1.8 }
+    }
+}
+
+// Colours for all 64 palette indices under each of the 8 emphasis combinations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[Rgb; PALETTE_SIZE]>,
+}
+
+impl Palette {
+    // Decodes the 2C02 composite signal the way an ideal NTSC television would,
+    // averaging a full subcarrier period of 12 samples per colour
+    pub fn generate(params: &NtscParams) -> Palette {
+        let colors = (0..EMPHASIS_VARIANTS)
+            .map(|emphasis| {
+                let mut variant = [(0, 0, 0); PALETTE_SIZE];
+                for (index, color) in variant.iter_mut().enumerate() {
+                    *color = ntsc_color(index as u8, emphasis as u8, params);
+                }
+                variant
+            })
+            .collect();
+        Palette { colors }
+    }
+
+    // 192 byte files hold the 64 base colours, emphasis is then approximated by
+    // dimming the other channels. 1536 byte files hold all 8 emphasis variants.
+    pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+        let variants = match data.len() {
+            192 => 1,
+            1536 => EMPHASIS_VARIANTS,
+            len => return Err(format!("palette must be 192 or 1536 bytes, got {}", len)),
+        };
+
+        let mut colors: Vec<[Rgb; PALETTE_SIZE]> = data
+            .chunks(PALETTE_SIZE * 3)
+            .take(variants)
+            .map(|chunk| {
+                let mut variant = [(0, 0, 0); PALETTE_SIZE];
+                for (color, rgb) in variant.iter_mut().zip(chunk.chunks(3)) {
+                    *color = (rgb[0], rgb[1], rgb[2]);
+                }
+                variant
+            })
+            .collect();
+
+        for emphasis in colors.len()..EMPHASIS_VARIANTS {
+            let variant = colors[0].map(|color| emphasize(color, emphasis as u8));
+            colors.push(variant);
+        }
+        Ok(Palette { colors })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, String> {
+        let data = fs::read(path).map_err(|err| err.to_string())?;
+        Palette::from_pal(&data)
+    }
+
+    // Colour of a palette index as output under the given PPUMASK value
+    pub fn color(&self, index: u8, mask: u8) -> Rgb {
+        let index = if mask & MASK_GREYSCALE != 0 { index & 0x30 } else { index & 0x3F };
+        let emphasis = (mask >> MASK_EMPHASIS_SHIFT) as usize;
+        self.colors[emphasis][index as usize]
+    }
+
+    // Colour of a PPU style pixel built by `pixel`
+    pub fn pixel_color(&self, pixel: u16) -> Rgb {
+        let pixel = output_pixel(pixel);
+        self.colors[(pixel >> 6) as usize][(pixel & 0x3F) as usize]
+    }
+
+    // RGB24 image of PPU style pixels
+    pub fn render(&self, pixels: &[u16]) -> Vec<u8> {
+        pixels
+            .iter()
+            .flat_map(|&pixel| {
+                let (r, g, b) = self.pixel_color(pixel);
+                [r, g, b]
+            })
+            .collect()
+    }
+
+    // 1536 byte .pal layout
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|variant| variant.iter().flat_map(|&(r, g, b)| [r, g, b]))
+            .collect()
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::generate(&NtscParams::default())
+    }
+}
+
+// PPU style pixel: palette index in bits 0-5, the PPUMASK emphasis bits in 6-8
+// and its greyscale bit in bit 9
+pub fn pixel(index: u8, mask: u8) -> u16 {
+    let emphasis = (mask >> MASK_EMPHASIS_SHIFT) as u16;
+    let greyscale = if mask & MASK_GREYSCALE != 0 { PIXEL_GREYSCALE } else { 0 };
+    (index & 0x3F) as u16 | emphasis << 6 | greyscale
+}
+
+// What the PPU puts out for a pixel, greyscale keeps only the luma column.
+// The result fits in 9 bits, index and emphasis.
+pub(crate) fn output_pixel(pixel: u16) -> u16 {
+    if pixel & PIXEL_GREYSCALE != 0 { pixel & 0x1F0 } else { pixel & 0x1FF }
+}
+
+fn ntsc_color(index: u8, emphasis: u8, params: &NtscParams) -> Rgb {
+    let pixel = index as u16 | (emphasis as u16) << 6;
+    let hue_offset = params.hue / 30.0;
+
+    let (mut y, mut i, mut q) = (0.0_f32, 0.0_f32, 0.0_f32);
+    for phase in 0..12_u8 {
+        let mut v = composite_level(pixel, phase);
+        v = (v - 0.5) * params.contrast + 0.5;
+        v *= params.brightness / 12.0;
+
+        let angle = PI / 6.0 * (phase as f32 + hue_offset);
+        y += v;
+        i += v * angle.cos();
+        q += v * angle.sin();
+    }
+    yiq_to_rgb(y, i * params.saturation, q * params.saturation, params.gamma)
+}
+
+// Composite voltage of a pixel (palette index in bits 0-5, emphasis in bits 6-8) at one
+// of the 12 colour subcarrier phases, normalised so black is 0 and white is 1
+pub(crate) fn composite_level(pixel: u16, phase: u8) -> f32 {
+    let hue = (pixel & 0x0F) as u8;
+    let emphasis = (pixel >> 6) & 7;
+    // $xE and $xF are black whatever the luma bits say
+    let level = if hue < 0x0E { ((pixel >> 4) & 3) as usize } else { 1 };
+    let low = if hue == 0x00 { SIGNAL_HIGH[level] } else { SIGNAL_LOW[level] };
+    let high = if hue < 0x0D { SIGNAL_HIGH[level] } else { SIGNAL_LOW[level] };
+
+    // Each colour is a square wave between two voltages, the emphasis bits reuse hues 12, 4 and 8
+    let in_phase = |hue: u8| (hue + phase + 8) % 12 < 6;
+    let mut signal = if in_phase(hue) { high } else { low };
+    if (emphasis & 1 != 0 && in_phase(12)) || (emphasis & 2 != 0 && in_phase(4)) || (emphasis & 4 != 0 && in_phase(8)) {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+// FCC YIQ to RGB with gamma correction
+pub(crate) fn yiq_to_rgb(y: f32, i: f32, q: f32, gamma: f32) -> Rgb {
+    let channel = |value: f32| {
+        let corrected = if value <= 0.0 { 0.0 } else { value.powf(2.2 / gamma) };
+        (255.95 * corrected).clamp(0.0, 255.0) as u8
+    };
+    (
+        channel(y + 0.946882 * i + 0.623557 * q),
+        channel(y - 0.274788 * i - 0.635691 * q),
+        channel(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+// Each emphasis bit (red, green, blue) dims the two other channels
+fn emphasize((mut r, mut g, mut b): Rgb, emphasis: u8) -> Rgb {
+    let dim = |value: u8| (value as f32 * EMPHASIS_ATTENUATION) as u8;
+    if emphasis & 1 != 0 {
+        g = dim(g);
+        b = dim(b);
+    }
+    if emphasis & 2 != 0 {
+        r = dim(r);
+        b = dim(b);
+    }
+    if emphasis & 4 != 0 {
+        r = dim(r);
+        g = dim(g);
+    }
+    (r, g, b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generated_palette() {
+        let palette = Palette::default();
+        assert_eq!(palette.color(0x0F, 0), (0, 0, 0));
+        assert_eq!(palette.color(0x1D, 0), (0, 0, 0));
+        let (r, g, b) = palette.color(0x30, 0);
+        assert!(r > 240 && g > 240 && b > 240);
+
+        // $16 is the red used by Mario
+        let (r, g, b) = palette.color(0x16, 0);
+        assert!(r > g && r > b);
+
+        // Greyscale keeps only the luma column, red emphasis dims green and blue
+        assert_eq!(palette.color(0x16, MASK_GREYSCALE), palette.color(0x10, 0));
+        let (r, g, _) = palette.color(0x30, 0b0010_0000);
+        assert!(g < r);
+
+        // Rendered pixels carry both PPUMASK effects
+        let mask = MASK_GREYSCALE | 0b0010_0000;
+        assert_eq!(palette.render(&[pixel(0x16, mask)]), {
+            let (r, g, b) = palette.color(0x16, mask);
+            vec![r, g, b]
+        });
+        assert_eq!(palette.pixel_color(pixel(0x16, MASK_GREYSCALE)), palette.color(0x10, 0));
+    }
+
+    #[test]
+    fn test_pal_files() {
+        let mut data = vec![0; 192];
+        data[0x21 * 3..0x21 * 3 + 3].copy_from_slice(&[100, 200, 250]);
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.color(0x21, 0), (100, 200, 250));
+        assert_eq!(palette.color(0x21, 0b1000_0000), (74, 149, 250));
+
+        let full = Palette::default().to_pal();
+        assert_eq!(full.len(), 1536);
+        assert_eq!(Palette::from_pal(&full).unwrap(), Palette::default());
+        assert!(Palette::from_pal(&data[..191]).is_err());
+    }
+}
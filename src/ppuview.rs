@@ -0,0 +1,76 @@
+// Debug views of PPU data as PPU style pixels, rendered through a Palette.
+// This is only the CHR ROM pattern table viewer. Nametables with the scroll
+// window, palette RAM, OAM, scanline refresh and their PNG export need a PPU,
+// until then tiles are coloured with the fixed VIEW_PALETTES.
+const TILE_BYTES: usize = 16;
+const TILE_SIZE: usize = 8;
+const TILES_PER_ROW: usize = 16;
+const PATTERN_TABLE_BYTES: usize = 0x1000;
+const CHR_BANK_BYTES: usize = 2 * PATTERN_TABLE_BYTES;
+
+// Both pattern tables of an 8KB bank side by side, $0000 on the left
+pub const PATTERN_VIEW_WIDTH: usize = 2 * TILES_PER_ROW * TILE_SIZE;
+pub const PATTERN_VIEW_HEIGHT: usize = TILES_PER_ROW * TILE_SIZE;
+
+// Colours for pixel values 0-3 while no palette RAM says otherwise: greys,
+// then the reds, greens and blues of typical sprite palettes
+pub const VIEW_PALETTES: [[u8; 4]; 4] = [
+    [0x0F, 0x00, 0x10, 0x30],
+    [0x0F, 0x06, 0x16, 0x27],
+    [0x0F, 0x09, 0x19, 0x29],
+    [0x0F, 0x01, 0x11, 0x21],
+];
+
+// Number of 8KB banks in CHR ROM, a partial last bank counts
+pub fn chr_banks(chr: &[u8]) -> usize {
+    chr.len().div_ceil(CHR_BANK_BYTES)
+}
+
+// Tiles past the end of CHR ROM are drawn in colour 0
+pub fn pattern_tables(chr: &[u8], bank: usize, colors: [u8; 4]) -> Vec<u16> {
+    let mut pixels = vec![colors[0] as u16; PATTERN_VIEW_WIDTH * PATTERN_VIEW_HEIGHT];
+    let start = bank * CHR_BANK_BYTES;
+    let tiles = chr.get(start..).unwrap_or(&[]).chunks_exact(TILE_BYTES).take(CHR_BANK_BYTES / TILE_BYTES);
+
+    for (index, tile) in tiles.enumerate() {
+        let table = index / (PATTERN_TABLE_BYTES / TILE_BYTES);
+        let column = table * TILES_PER_ROW + index % TILES_PER_ROW;
+        let row = index / TILES_PER_ROW % TILES_PER_ROW;
+        for y in 0..TILE_SIZE {
+            // Low bit plane first, then the high plane, leftmost pixel in bit 7
+            let (low, high) = (tile[y], tile[y + TILE_SIZE]);
+            for x in 0..TILE_SIZE {
+                let value = ((low >> (7 - x)) & 1) | (((high >> (7 - x)) & 1) << 1);
+                let offset = (row * TILE_SIZE + y) * PATTERN_VIEW_WIDTH + column * TILE_SIZE + x;
+                pixels[offset] = colors[value as usize] as u16;
+            }
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern_tables() {
+        let mut chr = vec![0; CHR_BANK_BYTES + TILE_BYTES];
+        // Tile 1 of the first table: top row 0,1,2,3,0,0,0,0
+        chr[TILE_BYTES] = 0b0101_0000;
+        chr[TILE_BYTES + TILE_SIZE] = 0b0011_0000;
+        // Tile 0 of the second table: bottom right pixel 3
+        chr[PATTERN_TABLE_BYTES + 7] = 1;
+        chr[PATTERN_TABLE_BYTES + 15] = 1;
+        let colors = [0x0F, 0x01, 0x02, 0x03];
+
+        let pixels = pattern_tables(&chr, 0, colors);
+        assert_eq!(pixels.len(), PATTERN_VIEW_WIDTH * PATTERN_VIEW_HEIGHT);
+        assert_eq!(pixels[8..13], [0x0F, 0x01, 0x02, 0x03, 0x0F]);
+        assert_eq!(pixels[7 * PATTERN_VIEW_WIDTH + 128 + 7], 0x03);
+
+        assert_eq!(chr_banks(&chr), 2);
+        assert!(pattern_tables(&chr, 1, colors).iter().all(|&pixel| pixel == 0x0F));
+        assert!(pattern_tables(&chr, 5, colors).iter().all(|&pixel| pixel == 0x0F));
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::cpu::CPU;
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+const NMI_VECTOR: u16 = 0xFFFA;
+const HOT_ADDRESSES: usize = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AddressStats {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoutineStats {
+    pub calls: u64,
+    // Cycles of the routine's own instructions
+    pub self_cycles: u64,
+    // Including everything it called, recursion counted once
+    pub total_cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub cycles: u64,
+    // Spent in NMI handlers and the routines they called
+    pub interrupt_cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StackFrame {
+    routine: u16,
+    interrupt: bool,
+}
+
+// Instruction about to run, accounted once the CPU has executed it
+struct Pending {
+    pc: u16,
+    opcode: u8,
+    cycles: u64,
+}
+
+// Attributes executed cycles to addresses and to a call tree rebuilt from
+// JSR/RTS and NMI/RTI. Call before_instruction from the run callback and
+// end_frame after each frame.
+#[derive(Default)]
+pub struct Profiler {
+    addresses: HashMap<u16, AddressStats>,
+    routines: HashMap<u16, RoutineStats>,
+    call_stack: Vec<StackFrame>,
+    // Cycles per distinct call stack, interned so stepping does not allocate
+    stack_ids: HashMap<Vec<StackFrame>, usize>,
+    stacks: Vec<(Vec<StackFrame>, u64)>,
+    current_stack: usize,
+    pending: Option<Pending>,
+    frames: Vec<FrameStats>,
+    frame: FrameStats,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    pub fn before_instruction(&mut self, cpu: &CPU) {
+        self.settle(cpu);
+
+        // The root of the tree is wherever execution started, usually the reset handler
+        if self.call_stack.is_empty() {
+            self.enter(cpu.program_counter, false);
+        }
+        let pc = if cpu.nmi_pending() {
+            let handler = cpu.peek_u16(NMI_VECTOR);
+            self.enter(handler, true);
+            handler
+        } else {
+            cpu.program_counter
+        };
+        self.pending = Some(Pending { pc, opcode: cpu.peek(pc), cycles: cpu.cycles });
+    }
+
+    pub fn end_frame(&mut self, cpu: &CPU) {
+        self.settle(cpu);
+        self.frames.push(self.frame);
+        self.frame = FrameStats::default();
+    }
+
+    pub fn addresses(&self) -> &HashMap<u16, AddressStats> {
+        &self.addresses
+    }
+
+    pub fn routines(&self) -> &HashMap<u16, RoutineStats> {
+        &self.routines
+    }
+
+    pub fn frames(&self) -> &[FrameStats] {
+        &self.frames
+    }
+
+    fn settle(&mut self, cpu: &CPU) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let cycles = cpu.cycles - pending.cycles;
+
+        let stats = self.addresses.entry(pending.pc).or_default();
+        stats.instructions += 1;
+        stats.cycles += cycles;
+        self.stacks[self.current_stack].1 += cycles;
+        self.frame.cycles += cycles;
+        if self.call_stack.iter().any(|frame| frame.interrupt) {
+            self.frame.interrupt_cycles += cycles;
+        }
+
+        let top = self.call_stack.len() - 1;
+        self.routines.entry(self.call_stack[top].routine).or_default().self_cycles += cycles;
+        for (i, frame) in self.call_stack.iter().enumerate() {
+            if !self.call_stack[..i].iter().any(|outer| outer.routine == frame.routine) {
+                self.routines.entry(frame.routine).or_default().total_cycles += cycles;
+            }
+        }
+
+        // Games that juggle the stack can return past what was seen, the root always stays
+        match pending.opcode {
+            JSR => self.enter(cpu.program_counter, false),
+            RTS if top > 0 && !self.call_stack[top].interrupt => self.leave(1),
+            RTI => {
+                if let Some(depth) = self.call_stack.iter().rposition(|frame| frame.interrupt) {
+                    self.leave(self.call_stack.len() - depth);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn enter(&mut self, routine: u16, interrupt: bool) {
+        self.call_stack.push(StackFrame { routine, interrupt });
+        self.routines.entry(routine).or_default().calls += 1;
+        self.update_stack_id();
+    }
+
+    fn leave(&mut self, frames: usize) {
+        self.call_stack.truncate(self.call_stack.len() - frames);
+        self.update_stack_id();
+    }
+
+    fn update_stack_id(&mut self) {
+        self.current_stack = match self.stack_ids.get(&self.call_stack) {
+            Some(&id) => id,
+            None => {
+                let id = self.stacks.len();
+                self.stacks.push((self.call_stack.clone(), 0));
+                self.stack_ids.insert(self.call_stack.clone(), id);
+                id
+            }
+        };
+    }
+
+    // Human readable summary. NMI handlers running longer than `vblank_cycles`
+    // are listed, their PPU updates would spill into rendering.
+    pub fn write_report<W: Write>(&self, mut out: W, vblank_cycles: u64) -> io::Result<()> {
+        let total: u64 = self.frames.iter().map(|frame| frame.cycles).sum();
+        let percent = |cycles: u64| if total == 0 { 0.0 } else { cycles as f64 * 100.0 / total as f64 };
+
+        writeln!(out, "Frames: {}, cycles: {}", self.frames.len(), total)?;
+        if let Some((index, max)) = self.frames.iter().enumerate().max_by_key(|(_, frame)| frame.cycles) {
+            writeln!(out, "Average {} cycles per frame, longest frame {} with {}", total / self.frames.len() as u64, index, max.cycles)?;
+        }
+        let overruns = self.frames.iter().filter(|frame| frame.interrupt_cycles > vblank_cycles).count();
+        writeln!(out, "NMI over the {} cycle vblank in {} frames", vblank_cycles, overruns)?;
+
+        let mut routines: Vec<_> = self.routines.iter().collect();
+        routines.sort_by_key(|(addr, stats)| (std::cmp::Reverse(stats.self_cycles), **addr));
+        writeln!(out, "\nRoutines          calls   self cycles      %  total cycles      %")?;
+        for (addr, stats) in routines {
+            writeln!(
+                out, "  ${:04X} {:>12} {:>13} {:>6.2} {:>13} {:>6.2}",
+                addr, stats.calls, stats.self_cycles, percent(stats.self_cycles),
+                stats.total_cycles, percent(stats.total_cycles),
+            )?;
+        }
+
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(addr, stats)| (std::cmp::Reverse(stats.cycles), **addr));
+        writeln!(out, "\nHottest instructions  executed        cycles      %")?;
+        for (addr, stats) in addresses.into_iter().take(HOT_ADDRESSES) {
+            writeln!(out, "  ${:04X} {:>22} {:>13} {:>6.2}", addr, stats.instructions, stats.cycles, percent(stats.cycles))?;
+        }
+
+        writeln!(out, "\nFrame        cycles    nmi cycles")?;
+        for (index, frame) in self.frames.iter().enumerate() {
+            let overrun = if frame.interrupt_cycles > vblank_cycles { "  over vblank" } else { "" };
+            writeln!(out, "  {:<6} {:>10} {:>13}{}", index, frame.cycles, frame.interrupt_cycles, overrun)?;
+        }
+        Ok(())
+    }
+
+    // One `outer;inner cycles` line per call stack, the input flamegraph.pl and
+    // inferno expect. NMI handlers are marked with an `nmi:` prefix.
+    pub fn write_collapsed<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .filter(|(_, cycles)| *cycles > 0)
+            .map(|(stack, cycles)| {
+                let names: Vec<String> = stack
+                    .iter()
+                    .map(|frame| format!("{}${:04X}", if frame.interrupt { "nmi:" } else { "" }, frame.routine))
+                    .collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::MEM;
+    use crate::observer::AccessKind;
+
+    #[test]
+    fn test_call_tree_and_interrupts() {
+        let mut cpu = CPU::new();
+        // $0600: JSR $0607; JSR $0607; BRK; $0607: INX; RTS
+        cpu.load(vec![0x20, 0x07, 0x06, 0x20, 0x07, 0x06, 0x00, 0xe8, 0x60]).unwrap();
+        cpu.reset();
+        cpu.mem_write_u16(NMI_VECTOR, 0x0610);
+        // $0610: RTI
+        cpu.mem_write(0x0610, 0x40);
+
+        // Profiling must not show up as reads of the opcodes it looks at
+        let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = reads.clone();
+        cpu.observers.add(AccessKind::READ, 0x0607..=0x0608, move |_| counter.set(counter.get() + 1));
+
+        let mut profiler = Profiler::new();
+        let mut steps = 0;
+        cpu.run_with_callback(|cpu| {
+            steps += 1;
+            if steps == 2 {
+                cpu.trigger_nmi();
+            }
+            profiler.before_instruction(cpu);
+        })
+        .unwrap();
+        profiler.end_frame(&cpu);
+        assert_eq!(reads.get(), 0);
+
+        let routine = profiler.routines()[&0x0607];
+        assert_eq!(routine.calls, 2);
+        assert_eq!(routine.self_cycles, 2 * (2 + 6));
+        assert_eq!(profiler.routines()[&0x0610].calls, 1);
+        assert_eq!(profiler.routines()[&0x0600].total_cycles, profiler.frames()[0].cycles);
+        assert_eq!(profiler.frames()[0].interrupt_cycles, 7 + 6);
+        assert_eq!(profiler.addresses()[&0x0607].instructions, 2);
+
+        let mut collapsed = Vec::new();
+        profiler.write_collapsed(&mut collapsed).unwrap();
+        let collapsed = String::from_utf8(collapsed).unwrap();
+        assert!(collapsed.contains("$0600;$0607 16\n"));
+        assert!(collapsed.contains("$0600;$0607;nmi:$0610 13\n"));
+
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, 10).unwrap();
+        assert!(String::from_utf8(report).unwrap().contains("NMI over the 10 cycle vblank in 1 frames"));
+    }
+}
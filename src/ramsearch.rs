@@ -0,0 +1,164 @@
+use std::ops::RangeInclusive;
+
+use crate::cpu::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    I8,
+    // 16 bit values are little endian and start at the candidate address
+    U16,
+    I16,
+}
+
+impl ValueType {
+    fn width(&self) -> u16 {
+        match self {
+            ValueType::U8 | ValueType::I8 => 1,
+            ValueType::U16 | ValueType::I16 => 2,
+        }
+    }
+
+    // Peeks so snapshots neither clock the controllers nor reach observers
+    fn read(&self, cpu: &CPU, addr: u16) -> i32 {
+        match self {
+            ValueType::U8 => cpu.peek(addr) as i32,
+            ValueType::I8 => cpu.peek(addr) as i8 as i32,
+            ValueType::U16 => cpu.peek_u16(addr) as i32,
+            ValueType::I16 => cpu.peek_u16(addr) as i16 as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+    GreaterOrEqual,
+    LessOrEqual,
+}
+
+impl Comparison {
+    fn holds(&self, current: i32, reference: i32) -> bool {
+        match self {
+            Comparison::Equal => current == reference,
+            Comparison::NotEqual => current != reference,
+            Comparison::Greater => current > reference,
+            Comparison::Less => current < reference,
+            Comparison::GreaterOrEqual => current >= reference,
+            Comparison::LessOrEqual => current <= reference,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    // The value each candidate had at the previous snapshot
+    Previous,
+    Value(i32),
+}
+
+// Iteratively narrows a memory range down to the addresses holding a game variable.
+// Every filter keeps the candidates passing the comparison and takes a new snapshot.
+pub struct RamSearch {
+    value_type: ValueType,
+    candidates: Vec<u16>,
+    previous: Vec<i32>,
+}
+
+impl RamSearch {
+    pub fn new(cpu: &CPU, range: RangeInclusive<u16>, value_type: ValueType) -> Self {
+        let (start, end) = range.into_inner();
+        let last = end.saturating_sub(value_type.width() - 1);
+        let candidates: Vec<u16> = if start <= last { (start..=last).collect() } else { Vec::new() };
+        let previous = candidates.iter().map(|&addr| value_type.read(cpu, addr)).collect();
+
+        RamSearch { value_type, candidates, previous }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    // Returns the number of remaining candidates
+    pub fn filter(&mut self, cpu: &CPU, comparison: Comparison, operand: Operand) -> usize {
+        let value_type = self.value_type;
+        let mut candidates = Vec::new();
+        let mut previous = Vec::new();
+
+        for (&addr, &last) in self.candidates.iter().zip(self.previous.iter()) {
+            let current = value_type.read(cpu, addr);
+            let reference = match operand {
+                Operand::Previous => last,
+                Operand::Value(value) => value,
+            };
+            if comparison.holds(current, reference) {
+                candidates.push(addr);
+                previous.push(current);
+            }
+        }
+
+        self.candidates = candidates;
+        self.previous = previous;
+        self.candidates.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    // Remaining addresses with their value at the last snapshot
+    pub fn candidates(&self) -> impl Iterator<Item = (u16, i32)> + '_ {
+        self.candidates.iter().copied().zip(self.previous.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::MEM;
+    use crate::joypad::JoypadButton;
+
+    #[test]
+    fn test_narrow_down_changing_byte() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x0075, 3);
+        cpu.mem_write(0x0076, 3);
+
+        let mut search = RamSearch::new(&cpu, 0x0000..=0x07FF, ValueType::U8);
+        assert_eq!(search.len(), 0x0800);
+
+        // Lose a life
+        cpu.mem_write(0x0075, 2);
+        assert_eq!(search.filter(&cpu, Comparison::Less, Operand::Previous), 1);
+        assert_eq!(search.candidates().collect::<Vec<_>>(), vec![(0x0075, 2)]);
+
+        assert_eq!(search.filter(&cpu, Comparison::Equal, Operand::Value(2)), 1);
+        assert_eq!(search.filter(&cpu, Comparison::NotEqual, Operand::Previous), 0);
+
+        // Searching over the controller ports does not shift out their buttons
+        cpu.joypad1.set_buttons(JoypadButton::BUTTON_A);
+        RamSearch::new(&cpu, 0x4016..=0x4017, ValueType::U8);
+        assert_eq!(cpu.mem_read(0x4016) & 1, 1);
+    }
+
+    #[test]
+    fn test_signed_word_search() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0x0010, 0xFFFE);
+
+        let mut search = RamSearch::new(&cpu, 0x0000..=0x00FF, ValueType::I16);
+        assert_eq!(search.len(), 0xFF);
+        assert_eq!(search.filter(&cpu, Comparison::Equal, Operand::Value(-2)), 1);
+
+        let mut search = RamSearch::new(&cpu, 0x0000..=0x00FF, ValueType::U16);
+        assert_eq!(search.filter(&cpu, Comparison::GreaterOrEqual, Operand::Value(0xFF00)), 1);
+        assert_eq!(search.candidates().next(), Some((0x0010, 0xFFFE)));
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const WAV_HEADER_LEN: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    // YUV 4:4:4, playable by ffmpeg and most video tools
+    Y4m,
+    // Untouched RGB24 frames plus a text sidecar describing them, the lossless option
+    RawRgb,
+}
+
+pub struct VideoWriter<W: Write> {
+    out: W,
+    format: VideoFormat,
+    frame_len: usize,
+}
+
+impl<W: Write> VideoWriter<W> {
+    pub fn new(mut out: W, format: VideoFormat, width: usize, height: usize, frame_rate: (u64, u64)) -> io::Result<Self> {
+        if format == VideoFormat::Y4m {
+            writeln!(out, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", width, height, frame_rate.0, frame_rate.1)?;
+        }
+        Ok(VideoWriter { out, format, frame_len: width * height * 3 })
+    }
+
+    pub fn write_frame(&mut self, rgb: &[u8]) -> io::Result<()> {
+        if rgb.len() != self.frame_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame size changed during recording"));
+        }
+
+        match self.format {
+            VideoFormat::RawRgb => self.out.write_all(rgb),
+            VideoFormat::Y4m => {
+                // BT.601 studio range, planes one after another
+                let pixels = rgb.chunks_exact(3).map(|p| (p[0] as i32, p[1] as i32, p[2] as i32));
+                let y: Vec<u8> = pixels.clone().map(|(r, g, b)| (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8).collect();
+                let u: Vec<u8> = pixels.clone().map(|(r, g, b)| (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8).collect();
+                let v: Vec<u8> = pixels.map(|(r, g, b)| (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8).collect();
+
+                self.out.write_all(b"FRAME\n")?;
+                self.out.write_all(&y)?;
+                self.out.write_all(&u)?;
+                self.out.write_all(&v)
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// 16 bit mono PCM. Sizes in the header are filled in by finish.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        let block_align = BITS_PER_SAMPLE / 8;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(WAV_HEADER_LEN - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16_u32.to_le_bytes())?;
+        out.write_all(&1_u16.to_le_bytes())?;
+        out.write_all(&1_u16.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0_u32.to_le_bytes())?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_len = self.samples * (BITS_PER_SAMPLE / 8) as u32;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Records every produced frame to `<base>.y4m` (or `<base>.rgb` with `<base>.txt`)
+// and its audio to `<base>.wav`. Frames with fewer samples than their share of the
+// sample rate are padded with silence so audio stays in sync with video.
+pub struct Recorder {
+    video: VideoWriter<BufWriter<File>>,
+    audio: WavWriter<BufWriter<File>>,
+    frame_rate: (u64, u64),
+    sample_rate: u32,
+    frames: u64,
+    samples: u64,
+}
+
+impl Recorder {
+    pub fn start<P: AsRef<Path>>(
+        base: P,
+        format: VideoFormat,
+        width: usize,
+        height: usize,
+        frame_rate: (u64, u64),
+        sample_rate: u32,
+    ) -> io::Result<Recorder> {
+        let base = base.as_ref();
+        let video_path = match format {
+            VideoFormat::Y4m => base.with_extension("y4m"),
+            VideoFormat::RawRgb => {
+                let sidecar = format!(
+                    "format=rgb24\nwidth={}\nheight={}\nframe_rate={}/{}\n",
+                    width, height, frame_rate.0, frame_rate.1
+                );
+                fs::write(base.with_extension("txt"), sidecar)?;
+                base.with_extension("rgb")
+            }
+        };
+
+        let video = VideoWriter::new(BufWriter::new(File::create(video_path)?), format, width, height, frame_rate)?;
+        let audio = WavWriter::new(BufWriter::new(File::create(base.with_extension("wav"))?), sample_rate)?;
+        Ok(Recorder { video, audio, frame_rate, sample_rate, frames: 0, samples: 0 })
+    }
+
+    // Picks `<stem>-<n>` in the current directory, the first n not already taken
+    pub fn next_free_base(stem: &str) -> PathBuf {
+        (1..)
+            .map(|n| PathBuf::from(format!("{}-{}", stem, n)))
+            .find(|base| !base.with_extension("wav").exists())
+            .unwrap()
+    }
+
+    pub fn frame(&mut self, rgb: &[u8], audio: &[i16]) -> io::Result<()> {
+        self.video.write_frame(rgb)?;
+        self.audio.write_samples(audio)?;
+        self.frames += 1;
+        self.samples += audio.len() as u64;
+
+        let expected = self.frames * self.sample_rate as u64 * self.frame_rate.1 / self.frame_rate.0;
+        if self.samples < expected {
+            let silence = vec![0; (expected - self.samples) as usize];
+            self.audio.write_samples(&silence)?;
+            self.samples = expected;
+        }
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.video.finish()?;
+        self.audio.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_y4m_frames() {
+        let mut video = VideoWriter::new(Vec::new(), VideoFormat::Y4m, 2, 1, (60, 1)).unwrap();
+        video.write_frame(&[255, 255, 255, 0, 0, 0]).unwrap();
+        assert!(video.write_frame(&[0; 3]).is_err());
+
+        let out = video.finish().unwrap();
+        let header = b"YUV4MPEG2 W2 H1 F60:1 Ip A1:1 C444\nFRAME\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(&out[header.len()..], &[235, 16, 128, 128, 128, 128]);
+    }
+
+    #[test]
+    fn test_wav_header_sizes() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write_samples(&[1, -1, 2]).unwrap();
+        let out = wav.finish().unwrap().into_inner();
+
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[4..8], &(36_u32 + 6).to_le_bytes());
+        assert_eq!(&out[24..28], &44100_u32.to_le_bytes());
+        assert_eq!(&out[40..44], &6_u32.to_le_bytes());
+        assert_eq!(&out[44..], &[1, 0, 0xFF, 0xFF, 2, 0]);
+    }
+
+    #[test]
+    fn test_recorder_pads_missing_audio() {
+        let dir = std::env::temp_dir().join(format!("nes_recorder_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("capture");
+
+        let mut recorder = Recorder::start(&base, VideoFormat::RawRgb, 1, 1, (50, 1), 1000).unwrap();
+        recorder.frame(&[1, 2, 3], &[]).unwrap();
+        recorder.frame(&[4, 5, 6], &[7; 5]).unwrap();
+        assert_eq!(recorder.frames(), 2);
+        recorder.finish().unwrap();
+
+        assert_eq!(fs::read(base.with_extension("rgb")).unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        assert!(fs::read_to_string(base.with_extension("txt")).unwrap().contains("frame_rate=50/1"));
+        // 20 samples per frame at 1000Hz and 50fps
+        assert_eq!(fs::read(base.with_extension("wav")).unwrap().len(), 44 + 40 * 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+// Console timing differences between the NTSC, PAL and Dendy (Famiclone) systems.
+// Everything derives from the master clock: the CPU and PPU divide it down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+const DOTS_PER_SCANLINE: u64 = 341;
+// A frame ends on an instruction boundary, at most an interrupt and the longest
+// instruction past its budget. Anything more means a reset or state load changed
+// the cycle count mid-frame.
+const MAX_OVERSHOOT: u64 = 14;
+
+impl Region {
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn master_clock_hz(&self) -> u64 {
+        match self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal | Region::Dendy => 26_601_712,
+        }
+    }
+
+    pub fn cpu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    pub fn ppu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64 {
+        self.master_clock_hz() as f64 / self.cpu_divider() as f64
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Scanlines between the NMI and the pre-render line.
+    // Dendy keeps NTSC's vblank and pads the extra lines before it instead.
+    pub fn vblank_scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Dendy => 20,
+            Region::Pal => 70,
+        }
+    }
+
+    // First scanline of vblank, where the PPU raises NMI
+    pub fn vblank_start_scanline(&self) -> u16 {
+        self.scanlines_per_frame() - self.vblank_scanlines() - 1
+    }
+
+    // CPU cycles from the NMI to the end of vblank, the time an NMI handler has
+    // for PPU updates before rendering starts
+    pub fn vblank_cpu_cycles(&self) -> u64 {
+        self.vblank_scanlines() as u64 * DOTS_PER_SCANLINE * self.ppu_divider() / self.cpu_divider()
+    }
+
+    fn master_clocks_per_frame(&self) -> u64 {
+        self.scanlines_per_frame() as u64 * DOTS_PER_SCANLINE * self.ppu_divider()
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.master_clock_hz() as f64 / self.master_clocks_per_frame() as f64
+    }
+
+    // Exact frame rate as numerator and denominator, for container headers
+    pub fn frame_rate_fraction(&self) -> (u64, u64) {
+        let (mut a, mut b) = (self.master_clock_hz(), self.master_clocks_per_frame());
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        (self.master_clock_hz() / a, self.master_clocks_per_frame() / a)
+    }
+
+    pub fn cpu_cycles_per_frame(&self) -> f64 {
+        self.master_clocks_per_frame() as f64 / self.cpu_divider() as f64
+    }
+
+    // CPU cycles elapsed at the end of `frames` frames. Frames do not hold a whole
+    // number of CPU cycles, counting from zero keeps the fractions from drifting.
+    pub fn cpu_cycles_for_frames(&self, frames: u64) -> u64 {
+        frames * self.master_clocks_per_frame() / self.cpu_divider()
+    }
+}
+
+// Hands out the CPU cycles of each frame. Budgets follow the region's fractional
+// cycles per frame and take back what the previous frame ran over, without looking
+// at the CPU's total, which resets and state loads move. A frame cut short by a
+// breakpoint is not made up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameClock {
+    region: Region,
+    frames: u64,
+    budget: u64,
+    overshoot: u64,
+}
+
+impl FrameClock {
+    pub fn new(region: Region) -> Self {
+        FrameClock { region, frames: 0, budget: 0, overshoot: 0 }
+    }
+
+    // Cycles to run for the next frame
+    pub fn next_frame(&mut self) -> u64 {
+        let frame = self.region.cpu_cycles_for_frames(self.frames + 1) - self.region.cpu_cycles_for_frames(self.frames);
+        self.frames += 1;
+        self.budget = frame.saturating_sub(self.overshoot);
+        self.budget
+    }
+
+    // CPU cycle counts before and after running the frame's budget
+    pub fn frame_done(&mut self, before: u64, after: u64) {
+        self.overshoot = match after.checked_sub(before) {
+            Some(ran) if ran.saturating_sub(self.budget) <= MAX_OVERSHOOT => ran.saturating_sub(self.budget),
+            _ => 0,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_frame_timing() {
+        assert!((Region::Ntsc.frame_rate() - 60.1).abs() < 0.01);
+        assert!((Region::Pal.frame_rate() - 50.0).abs() < 0.01);
+        assert!((Region::Dendy.frame_rate() - 50.0).abs() < 0.01);
+
+        assert_eq!(Region::Ntsc.frame_rate_fraction(), (2684659, 44671));
+        assert_eq!(Region::Pal.cpu_cycles_per_frame(), 33247.5);
+        assert_eq!(Region::Dendy.cpu_cycles_per_frame(), 35464.0);
+        assert_eq!(Region::Pal.cpu_cycles_for_frames(1), 33247);
+        assert_eq!(Region::Pal.cpu_cycles_for_frames(2), 66495);
+
+        assert_eq!(Region::Ntsc.vblank_start_scanline(), 241);
+        assert_eq!(Region::Pal.vblank_start_scanline(), 241);
+        assert_eq!(Region::Dendy.vblank_start_scanline(), 291);
+        assert_eq!(Region::Ntsc.vblank_cpu_cycles(), 2273);
+        assert_eq!(Region::from_name("PAL"), Some(Region::Pal));
+    }
+
+    #[test]
+    fn test_frame_budget_after_reset_and_state_load() {
+        let mut cpu = CPU::new();
+        // $0600: JMP $0600
+        cpu.load(vec![0x4c, 0x00, 0x06]).unwrap();
+        cpu.reset();
+        let mut clock = FrameClock::new(Region::Pal);
+        let run_frame = |cpu: &mut CPU, clock: &mut FrameClock| {
+            let before = cpu.cycles;
+            cpu.run_for_cycles(clock.next_frame()).unwrap();
+            clock.frame_done(before, cpu.cycles);
+            cpu.cycles - before
+        };
+
+        // Two PAL frames hold 66495 cycles, give or take the last JMP
+        let state = cpu.save_state();
+        let ran = run_frame(&mut cpu, &mut clock) + run_frame(&mut cpu, &mut clock);
+        assert!((66495..66495 + 3).contains(&ran));
+        for _ in 0..8 {
+            run_frame(&mut cpu, &mut clock);
+        }
+
+        // Neither a reset nor going back to an older state changes the next frame's length
+        cpu.reset();
+        assert!((33245..33251).contains(&run_frame(&mut cpu, &mut clock)));
+        cpu.load_state(&state).unwrap();
+        assert!((33245..33251).contains(&run_frame(&mut cpu, &mut clock)));
+
+        // A reset in the middle of a frame is not taken for an overshoot
+        clock.next_frame();
+        clock.frame_done(200_000, 100);
+        assert!((33247..=33248).contains(&clock.next_frame()));
+    }
+}
@@ -0,0 +1,15 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+// Writes an RGB24 image as PNG
+pub fn save_png<P: AsRef<Path>>(path: P, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(rgb).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
@@ -0,0 +1,339 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Blob, Dynamic, Engine, EvalAltResult, FnPtr, Module, AST};
+
+use crate::cpu::{CPU, MEM};
+use crate::joypad::{Joypad, JoypadButton};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+type Register = (&'static str, fn(&CPU) -> i64);
+type RegisterSetter = (&'static str, fn(&mut CPU, i64));
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(io::Error),
+    Script(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Io(err) => write!(f, "{}", err),
+            ScriptError::Script(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<io::Error> for ScriptError {
+    fn from(err: io::Error) -> Self {
+        ScriptError::Io(err)
+    }
+}
+
+// Text a script asked to draw over the current frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptText {
+    pub x: i32,
+    pub y: i32,
+    pub text: String,
+}
+
+// State shared with the registered functions. The frontend's CPU is swapped
+// into `cpu` for as long as script code runs and swapped back afterwards.
+struct ScriptState {
+    cpu: CPU,
+    frame: i64,
+    frame_hooks: Vec<FnPtr>,
+    instruction_hooks: Vec<FnPtr>,
+    exec_hooks: HashMap<u16, Vec<FnPtr>>,
+    texts: Vec<ScriptText>,
+    stop_requested: bool,
+}
+
+// Runs a Rhai script against the emulator. Scripts see these modules:
+//   memory::read(addr) read_u16(addr) write(addr, value)
+//   cpu::a() x() y() sp() status() pc() cycles() set_a(v) set_x(v) set_y(v) set_pc(v)
+//   emu::on_frame(f) on_instruction(f) on_exec(addr, f) frame() stop()
+//   joypad::get(port) set(port, buttons) and the button constants joypad::A, joypad::UP, ...
+//   savestate::save() load(blob)
+//   gui::text(x, y, value)
+// The first error removes all hooks, it is reported through take_error.
+pub struct ScriptHost {
+    engine: Engine,
+    ast: AST,
+    state: Rc<RefCell<ScriptState>>,
+    error: Option<ScriptError>,
+}
+
+impl ScriptHost {
+    pub fn load<P: AsRef<Path>>(path: P, cpu: &mut CPU) -> Result<ScriptHost, ScriptError> {
+        ScriptHost::from_source(&fs::read_to_string(path)?, cpu)
+    }
+
+    // Compiles the script and runs its top level once, which installs the hooks
+    pub fn from_source(source: &str, cpu: &mut CPU) -> Result<ScriptHost, ScriptError> {
+        let state = Rc::new(RefCell::new(ScriptState {
+            cpu: CPU::new(),
+            frame: 0,
+            frame_hooks: Vec::new(),
+            instruction_hooks: Vec::new(),
+            exec_hooks: HashMap::new(),
+            texts: Vec::new(),
+            stop_requested: false,
+        }));
+
+        let mut engine = Engine::new();
+        register_api(&mut engine, &state);
+        let ast = engine.compile(source).map_err(|err| ScriptError::Script(err.to_string()))?;
+
+        let host = ScriptHost { engine, ast, state, error: None };
+        host.with_cpu(cpu, |engine, ast| engine.run_ast(ast))?;
+        Ok(host)
+    }
+
+    fn with_cpu<T, F>(&self, cpu: &mut CPU, f: F) -> Result<T, ScriptError>
+    where F: FnOnce(&Engine, &AST) -> ScriptResult<T> {
+        mem::swap(cpu, &mut self.state.borrow_mut().cpu);
+        let result = f(&self.engine, &self.ast);
+        mem::swap(cpu, &mut self.state.borrow_mut().cpu);
+        result.map_err(|err| ScriptError::Script(err.to_string()))
+    }
+
+    fn call_hooks(&mut self, cpu: &mut CPU, hooks: Vec<FnPtr>) {
+        if hooks.is_empty() {
+            return;
+        }
+        let result = self.with_cpu(cpu, |engine, ast| {
+            for hook in &hooks {
+                let _: Dynamic = hook.call(engine, ast, ())?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            let mut state = self.state.borrow_mut();
+            state.frame_hooks.clear();
+            state.instruction_hooks.clear();
+            state.exec_hooks.clear();
+            self.error = Some(err);
+        }
+    }
+
+    // Call once before emulating each frame. Text drawn by the previous frame is dropped.
+    pub fn frame(&mut self, cpu: &mut CPU) {
+        let hooks = {
+            let mut state = self.state.borrow_mut();
+            state.texts.clear();
+            state.frame_hooks.clone()
+        };
+        self.call_hooks(cpu, hooks);
+        self.state.borrow_mut().frame += 1;
+    }
+
+    // Whether before_instruction has anything to do, running without it is faster
+    pub fn wants_instructions(&self) -> bool {
+        let state = self.state.borrow();
+        !state.instruction_hooks.is_empty() || !state.exec_hooks.is_empty()
+    }
+
+    pub fn before_instruction(&mut self, cpu: &mut CPU) {
+        let hooks = {
+            let state = self.state.borrow();
+            let mut hooks = state.instruction_hooks.clone();
+            if let Some(exec) = state.exec_hooks.get(&cpu.program_counter) {
+                hooks.extend(exec.iter().cloned());
+            }
+            hooks
+        };
+        self.call_hooks(cpu, hooks);
+    }
+
+    pub fn take_texts(&mut self) -> Vec<ScriptText> {
+        mem::take(&mut self.state.borrow_mut().texts)
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.state.borrow().stop_requested
+    }
+
+    pub fn take_error(&mut self) -> Option<ScriptError> {
+        self.error.take()
+    }
+}
+
+fn joypad(cpu: &mut CPU, port: i64) -> ScriptResult<&mut Joypad> {
+    usize::try_from(port - 1)
+        .ok()
+        .and_then(|player| cpu.joypad_mut(player))
+        .ok_or_else(|| format!("no joypad on port {}", port).into())
+}
+
+fn register_api(engine: &mut Engine, state: &Rc<RefCell<ScriptState>>) {
+    let mut memory = Module::new();
+    let s = state.clone();
+    // Reads peek like the debugger, they neither clock controllers nor reach observers
+    memory.set_native_fn("read", move |addr: i64| Ok(s.borrow().cpu.peek(addr as u16) as i64));
+    let s = state.clone();
+    memory.set_native_fn("read_u16", move |addr: i64| Ok(s.borrow().cpu.peek_u16(addr as u16) as i64));
+    let s = state.clone();
+    memory.set_native_fn("write", move |addr: i64, value: i64| {
+        s.borrow_mut().cpu.mem_write(addr as u16, value as u8);
+        Ok(())
+    });
+    engine.register_static_module("memory", memory.into());
+
+    let mut cpu = Module::new();
+    let registers: [Register; 7] = [
+        ("a", |cpu| cpu.register_a as i64),
+        ("x", |cpu| cpu.register_x as i64),
+        ("y", |cpu| cpu.register_y as i64),
+        ("sp", |cpu| cpu.stack_pointer as i64),
+        ("status", |cpu| cpu.status.bits() as i64),
+        ("pc", |cpu| cpu.program_counter as i64),
+        ("cycles", |cpu| cpu.cycles as i64),
+    ];
+    for (name, read) in registers {
+        let s = state.clone();
+        cpu.set_native_fn(name, move || Ok(read(&s.borrow().cpu)));
+    }
+    let setters: [RegisterSetter; 4] = [
+        ("set_a", |cpu, v| cpu.register_a = v as u8),
+        ("set_x", |cpu, v| cpu.register_x = v as u8),
+        ("set_y", |cpu, v| cpu.register_y = v as u8),
+        ("set_pc", |cpu, v| cpu.program_counter = v as u16),
+    ];
+    for (name, write) in setters {
+        let s = state.clone();
+        cpu.set_native_fn(name, move |value: i64| {
+            write(&mut s.borrow_mut().cpu, value);
+            Ok(())
+        });
+    }
+    engine.register_static_module("cpu", cpu.into());
+
+    let mut emu = Module::new();
+    let s = state.clone();
+    emu.set_native_fn("on_frame", move |hook: FnPtr| {
+        s.borrow_mut().frame_hooks.push(hook);
+        Ok(())
+    });
+    let s = state.clone();
+    emu.set_native_fn("on_instruction", move |hook: FnPtr| {
+        s.borrow_mut().instruction_hooks.push(hook);
+        Ok(())
+    });
+    let s = state.clone();
+    emu.set_native_fn("on_exec", move |addr: i64, hook: FnPtr| {
+        s.borrow_mut().exec_hooks.entry(addr as u16).or_default().push(hook);
+        Ok(())
+    });
+    let s = state.clone();
+    emu.set_native_fn("frame", move || Ok(s.borrow().frame));
+    let s = state.clone();
+    emu.set_native_fn("stop", move || {
+        s.borrow_mut().stop_requested = true;
+        Ok(())
+    });
+    engine.register_static_module("emu", emu.into());
+
+    let mut pad = Module::new();
+    let buttons = [
+        ("A", JoypadButton::BUTTON_A),
+        ("B", JoypadButton::BUTTON_B),
+        ("SELECT", JoypadButton::SELECT),
+        ("START", JoypadButton::START),
+        ("UP", JoypadButton::UP),
+        ("DOWN", JoypadButton::DOWN),
+        ("LEFT", JoypadButton::LEFT),
+        ("RIGHT", JoypadButton::RIGHT),
+    ];
+    for (name, button) in buttons {
+        pad.set_var(name, button.bits() as i64);
+    }
+    let s = state.clone();
+    pad.set_native_fn("get", move |port: i64| Ok(joypad(&mut s.borrow_mut().cpu, port)?.buttons().bits() as i64));
+    let s = state.clone();
+    pad.set_native_fn("set", move |port: i64, buttons: i64| {
+        joypad(&mut s.borrow_mut().cpu, port)?.set_buttons(JoypadButton::from_bits_truncate(buttons as u8));
+        Ok(())
+    });
+    engine.register_static_module("joypad", pad.into());
+
+    let mut savestate = Module::new();
+    let s = state.clone();
+    savestate.set_native_fn("save", move || Ok(s.borrow().cpu.save_state() as Blob));
+    let s = state.clone();
+    savestate.set_native_fn("load", move |blob: Blob| {
+        s.borrow_mut().cpu.load_state(&blob).map_err(|err| err.to_string().into())
+    });
+    engine.register_static_module("savestate", savestate.into());
+
+    let mut gui = Module::new();
+    let s = state.clone();
+    gui.set_native_fn("text", move |x: i64, y: i64, value: Dynamic| {
+        s.borrow_mut().texts.push(ScriptText { x: x as i32, y: y as i32, text: value.to_string() });
+        Ok(())
+    });
+    engine.register_static_module("gui", gui.into());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_and_frame_hooks() {
+        let mut cpu = CPU::new();
+        let script = r#"
+            memory::write(0x10, 7);
+            emu::on_frame(|| {
+                memory::write(0x11, memory::read(0x10) + emu::frame());
+                joypad::set(1, joypad::A | joypad::RIGHT);
+                memory::read(0x4016);
+                gui::text(1, 2, "F" + emu::frame());
+            });
+        "#;
+        let mut host = ScriptHost::from_source(script, &mut cpu).unwrap();
+        assert_eq!(cpu.mem_read(0x10), 7);
+
+        host.frame(&mut cpu);
+        host.frame(&mut cpu);
+        assert_eq!(cpu.mem_read(0x11), 8);
+        assert_eq!(cpu.joypad1.buttons(), JoypadButton::BUTTON_A | JoypadButton::RIGHT);
+        // The script's reads of $4016 left the shift register at button A
+        assert_eq!(cpu.mem_read(0x4016), 1);
+        assert_eq!(host.take_texts(), vec![ScriptText { x: 1, y: 2, text: "F1".to_string() }]);
+        assert!(host.take_error().is_none());
+    }
+
+    #[test]
+    fn test_exec_hooks_and_errors() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0xe8, 0x00]).unwrap();
+        cpu.reset();
+
+        let script = r#"
+            let state = savestate::save();
+            emu::on_exec(0x0601, || { if cpu::x() == 1 { cpu::set_a(0x42); emu::stop(); } });
+            emu::on_exec(0x0602, || joypad::set(5, 0));
+        "#;
+        let mut host = ScriptHost::from_source(script, &mut cpu).unwrap();
+        assert!(host.wants_instructions());
+
+        cpu.run_with_callback(|cpu| host.before_instruction(cpu)).unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+        assert!(host.stop_requested());
+        assert!(host.take_error().unwrap().to_string().contains("no joypad on port 5"));
+        assert!(!host.wants_instructions());
+
+        assert!(ScriptHost::from_source("let x = ;", &mut cpu).is_err());
+    }
+}
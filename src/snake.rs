@@ -0,0 +1,142 @@
+use crate::cpu::{CpuError, CpuState, CPU, MEM};
+use crate::joypad::JoypadButton;
+use crate::palette;
+
+// Memory mapping used by the game:
+/* 0xFE - Input - Random Number Generator
+ * 0xFF - Input - Code of the last pressed button
+ * [0x0200..0x0600] - Output - Screen
+ * Each cell represents the color of a pixel in a 32x32 matrix.
+ * The matrix starts from top-left corner i.e.
+ * 0x0200 - color of (0, 0) pixel
+ * 0x0201 - color of (1, 0) pixel
+ * 0x0220 - color of (0, 1) pixel
+ * ...
+ * [0x0600..] - Game Code - Execution Code
+ * The game expects the execution code to be located right after the output region
+ */
+
+pub const GAME_CODE: &[u8] = &[
+    0x20, 0x06, 0x06, 0x20, 0x38,
+    0x06, 0x20, 0x0d, 0x06, 0x20,
+    0x2a, 0x06, 0x60, 0xa9, 0x02,
+    0x85, 0x02, 0xa9, 0x04, 0x85,
+    0x03, 0xa9, 0x11, 0x85, 0x10,
+    0xa9, 0x10, 0x85, 0x12, 0xa9,
+    0x0f, 0x85, 0x14, 0xa9, 0x04,
+    0x85, 0x11, 0x85, 0x13, 0x85,
+    0x15, 0x60, 0xa5, 0xfe, 0x85,
+    0x00, 0xa5, 0xfe, 0x29, 0x03,
+    0x18, 0x69, 0x02, 0x85, 0x01,
+    0x60, 0x20, 0x4d, 0x06, 0x20,
+    0x8d, 0x06, 0x20, 0xc3, 0x06,
+    0x20, 0x19, 0x07, 0x20, 0x20,
+    0x07, 0x20, 0x2d, 0x07, 0x4c,
+    0x38, 0x06, 0xa5, 0xff, 0xc9,
+    0x77, 0xf0, 0x0d, 0xc9, 0x64,
+    0xf0, 0x14, 0xc9, 0x73, 0xf0,
+    0x1b, 0xc9, 0x61, 0xf0, 0x22,
+    0x60, 0xa9, 0x04, 0x24, 0x02,
+    0xd0, 0x26, 0xa9, 0x01, 0x85,
+    0x02, 0x60, 0xa9, 0x08, 0x24,
+    0x02, 0xd0, 0x1b, 0xa9, 0x02,
+    0x85, 0x02, 0x60, 0xa9, 0x01,
+    0x24, 0x02, 0xd0, 0x10, 0xa9,
+    0x04, 0x85, 0x02, 0x60, 0xa9,
+    0x02, 0x24, 0x02, 0xd0, 0x05,
+    0xa9, 0x08, 0x85, 0x02, 0x60,
+    0x60, 0x20, 0x94, 0x06, 0x20,
+    0xa8, 0x06, 0x60, 0xa5, 0x00,
+    0xc5, 0x10, 0xd0, 0x0d, 0xa5,
+    0x01, 0xc5, 0x11, 0xd0, 0x07,
+    0xe6, 0x03, 0xe6, 0x03, 0x20,
+    0x2a, 0x06, 0x60, 0xa2, 0x02,
+    0xb5, 0x10, 0xc5, 0x10, 0xd0,
+    0x06, 0xb5, 0x11, 0xc5, 0x11,
+    0xf0, 0x09, 0xe8, 0xe8, 0xe4,
+    0x03, 0xf0, 0x06, 0x4c, 0xaa,
+    0x06, 0x4c, 0x35, 0x07, 0x60,
+    0xa6, 0x03, 0xca, 0x8a, 0xb5,
+    0x10, 0x95, 0x12, 0xca, 0x10,
+    0xf9, 0xa5, 0x02, 0x4a, 0xb0,
+    0x09, 0x4a, 0xb0, 0x19, 0x4a,
+    0xb0, 0x1f, 0x4a, 0xb0, 0x2f,
+    0xa5, 0x10, 0x38, 0xe9, 0x20,
+    0x85, 0x10, 0x90, 0x01, 0x60,
+    0xc6, 0x11, 0xa9, 0x01, 0xc5,
+    0x11, 0xf0, 0x28, 0x60, 0xe6,
+    0x10, 0xa9, 0x1f, 0x24, 0x10,
+    0xf0, 0x1f, 0x60, 0xa5, 0x10,
+    0x18, 0x69, 0x20, 0x85, 0x10,
+    0xb0, 0x01, 0x60, 0xe6, 0x11,
+    0xa9, 0x06, 0xc5, 0x11, 0xf0,
+    0x0c, 0x60, 0xc6, 0x10, 0xa5,
+    0x10, 0x29, 0x1f, 0xc9, 0x1f,
+    0xf0, 0x01, 0x60, 0x4c, 0x35,
+    0x07, 0xa0, 0x00, 0xa5, 0xfe,
+    0x91, 0x00, 0x60, 0xa6, 0x03,
+    0xa9, 0x00, 0x81, 0x10, 0xa2,
+    0x00, 0xa9, 0x01, 0x81, 0x10,
+    0x60, 0xa2, 0x00, 0xea, 0xea,
+    0xca, 0xd0, 0xfb, 0x60
+];
+
+pub const SCREEN_WIDTH: usize = 32;
+pub const SCREEN_HEIGHT: usize = 32;
+const SCREEN_START: u16 = 0x0200;
+const RANDOM: u16 = 0xFE;
+const LAST_KEY: u16 = 0xFF;
+// With no PPU the last value written to PPUMASK is still in memory
+const PPUMASK: u16 = 0x2001;
+
+// The demo is not NES timed, this budget per displayed frame keeps it playable
+pub const CYCLES_PER_FRAME: u64 = 600;
+
+// Palette index standing in for each colour the game writes to the screen
+pub fn palette_index(byte: u8) -> u8 {
+    match byte {
+        0 => 0x0F,
+        1 => 0x30,
+        2 | 9 => 0x00,
+        3 | 10 => 0x16,
+        4 | 11 => 0x2A,
+        5 | 12 => 0x12,
+        6 | 13 => 0x24,
+        7 | 14 => 0x28,
+        _ => 0x2C,
+    }
+}
+
+// Screen as PPU style pixels with the PPUMASK emphasis and greyscale bits
+pub fn screen_pixels(cpu: &CPU) -> Vec<u16> {
+    let mask = cpu.peek(PPUMASK);
+    (0..(SCREEN_WIDTH * SCREEN_HEIGHT) as u16)
+        .map(|i| palette::pixel(palette_index(cpu.peek(SCREEN_START + i)), mask))
+        .collect()
+}
+
+// The game reads the last pressed direction as an ASCII key code from mem[0xFF]
+pub fn write_input(cpu: &mut CPU) {
+    let buttons = cpu.joypad1.buttons();
+    let key = if buttons.contains(JoypadButton::UP) {
+        b'w'
+    } else if buttons.contains(JoypadButton::DOWN) {
+        b's'
+    } else if buttons.contains(JoypadButton::LEFT) {
+        b'a'
+    } else if buttons.contains(JoypadButton::RIGHT) {
+        b'd'
+    } else {
+        return;
+    };
+    cpu.mem_write(LAST_KEY, key);
+}
+
+// The game takes a fresh random number from mem[0xFE] before every instruction
+pub fn write_random(cpu: &mut CPU, value: u8) {
+    cpu.mem_write(RANDOM, value);
+}
+
+pub fn run_frame<R: FnMut() -> u8>(cpu: &mut CPU, mut random: R) -> Result<CpuState, CpuError> {
+    cpu.run_for_cycles_with_callback(CYCLES_PER_FRAME, |cpu| write_random(cpu, random()))
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const ROM_START: u16 = 0x8000;
+const ROM_WINDOW: usize = 0x8000;
+const PRG_RAM_START: u16 = 0x6000;
+const INES_HEADER_LEN: u64 = 16;
+// FCEUX writes one .nl file per 16KB PRG bank
+const NL_BANK_SIZE: usize = 0x4000;
+
+// What a label names: a CPU address, or a PRG ROM byte wherever it is mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Location {
+    Cpu(u16),
+    Prg(usize),
+}
+
+// Labels by location and by name. Addresses from $8000 up are turned into PRG
+// offsets through the cartridge mapping, so a label follows its bank.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    prg_len: usize,
+    labels: HashMap<Location, String>,
+    names: HashMap<String, Location>,
+}
+
+impl SymbolTable {
+    // `prg_len` is 0 for programs running without a cartridge
+    pub fn new(prg_len: usize) -> Self {
+        SymbolTable { prg_len, labels: HashMap::new(), names: HashMap::new() }
+    }
+
+    pub fn prg_len(&self) -> usize {
+        self.prg_len
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn location(&self, addr: u16) -> Location {
+        map_address(self.prg_len, addr)
+    }
+
+    // CPU address a location shows up at, the last mirror for PRG smaller than 32KB
+    pub fn address(&self, location: Location) -> u16 {
+        match location {
+            Location::Cpu(addr) => addr,
+            Location::Prg(offset) => {
+                let window = self.prg_len.clamp(1, ROM_WINDOW);
+                (0x10000 - window + offset % window) as u16
+            }
+        }
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&self.location(addr)).map(|name| name.as_str())
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<Location> {
+        self.names.get(name).copied()
+    }
+
+    // Later definitions of a name are ignored. Cheap local labels (@loop) only
+    // name a location nothing else names.
+    pub fn insert(&mut self, location: Location, name: &str) {
+        let location = match location {
+            Location::Cpu(addr) => self.location(addr),
+            prg => prg,
+        };
+        if !self.names.contains_key(name) {
+            self.names.insert(name.to_string(), location);
+        }
+        match self.labels.get(&location) {
+            Some(existing) if !existing.starts_with('@') || name.starts_with('@') => (),
+            _ => {
+                self.labels.insert(location, name.to_string());
+            }
+        }
+    }
+
+    // Picks the format from the extension: ca65 .dbg, FCEUX .nl, Mesen .mlb,
+    // anything else holds `addr=name` lines. Returns the number of labels read.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let before = self.len();
+
+        let result = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => self.parse_dbg(&text),
+            Some("nl") => self.parse_nl(&text, nl_bank(&name)),
+            Some("mlb") => self.parse_mlb(&text),
+            _ => self.parse_simple(&text),
+        };
+        result.map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(self.len() - before)
+    }
+
+    pub fn parse_simple(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (addr, name) = line.split_once('=').ok_or_else(|| format!("line {}: expected addr=name", number + 1))?;
+            let addr = parse_address(addr.trim()).ok_or_else(|| format!("line {}: bad address {:?}", number + 1, addr.trim()))?;
+            self.insert(Location::Cpu(addr), name.trim());
+        }
+        Ok(())
+    }
+
+    // `$C000#Name#comment` lines. With a bank the addresses are in that 16KB PRG
+    // bank, without one they are CPU addresses as in the .ram.nl file.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().unwrap_or("").trim();
+            let name = fields.next().unwrap_or("").trim();
+            if addr.is_empty() || name.is_empty() {
+                continue;
+            }
+            // Arrays are written as $0300/10
+            let addr = addr.split('/').next().unwrap_or(addr);
+            let addr = parse_address(addr).ok_or_else(|| format!("line {}: bad address {:?}", number + 1, addr))?;
+
+            let location = match bank {
+                Some(bank) if addr >= ROM_START => Location::Prg(bank * NL_BANK_SIZE + (addr as usize % NL_BANK_SIZE)),
+                _ => Location::Cpu(addr),
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+
+    // `type:addr[-end]:name[:comment]` lines, with Mesen 1 letters or Mesen 2 names
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.trim().splitn(4, ':').collect();
+            let [kind, addr, name, ..] = fields[..] else {
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+            let addr = addr.split('-').next().unwrap_or(addr);
+            let value = usize::from_str_radix(addr, 16).map_err(|_| format!("line {}: bad address {:?}", number + 1, addr))?;
+
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(value),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => Location::Cpu(value as u16),
+                "S" | "NesSaveRam" | "W" | "NesWorkRam" => Location::Cpu(PRG_RAM_START.wrapping_add(value as u16)),
+                _ => continue,
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+
+    // Labels of a ca65/ld65 debug file. Segments written to the ROM image carry
+    // their file offset, which gives the PRG offset of their labels.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), String> {
+        let mut segments: HashMap<&str, (u64, Option<u64>)> = HashMap::new();
+        let mut symbols = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let Some((kind, fields)) = parse_dbg_line(line) else {
+                continue;
+            };
+            let field = |key: &str| fields.get(key).copied();
+            let number_field = |key: &str| field(key).and_then(parse_number);
+            match kind {
+                "seg" => {
+                    let id = field("id").ok_or_else(|| format!("line {}: segment without id", number + 1))?;
+                    let start = number_field("start").unwrap_or(0);
+                    segments.insert(id, (start, number_field("ooffs")));
+                }
+                "sym" if field("type") == Some("lab") => {
+                    let (Some(name), Some(value)) = (field("name"), number_field("val")) else {
+                        return Err(format!("line {}: label without name or value", number + 1));
+                    };
+                    symbols.push((name, value, field("seg")));
+                }
+                _ => (),
+            }
+        }
+
+        for (name, value, segment) in symbols {
+            let location = match segment.and_then(|id| segments.get(id)) {
+                Some(&(start, ooffs)) => segment_location(start, ooffs, value.wrapping_sub(start)),
+                None => Location::Cpu(value as u16),
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+}
+
+// Only NROM is mapped so far, where PRG smaller than 32KB is mirrored
+pub(crate) fn map_address(prg_len: usize, addr: u16) -> Location {
+    if addr >= ROM_START && prg_len > 0 {
+        Location::Prg((addr - ROM_START) as usize % prg_len)
+    } else {
+        Location::Cpu(addr)
+    }
+}
+
+// Byte `offset` of a ca65 segment loaded at `start`. Segments written to the ROM
+// image carry their file offset `ooffs`, which gives the PRG offset.
+pub(crate) fn segment_location(start: u64, ooffs: Option<u64>, offset: u64) -> Location {
+    let addr = start.wrapping_add(offset);
+    match ooffs {
+        Some(ooffs) if addr >= ROM_START as u64 && ooffs >= INES_HEADER_LEN => {
+            Location::Prg((ooffs - INES_HEADER_LEN + offset) as usize)
+        }
+        _ => Location::Cpu(addr as u16),
+    }
+}
+
+// `game.nes.2.nl` describes bank 2, `game.nes.ram.nl` RAM
+fn nl_bank(file_name: &str) -> Option<usize> {
+    let stem = file_name.strip_suffix(".nl")?;
+    stem.rsplit('.').next()?.parse().ok()
+}
+
+// `$C000`, `0xC000` or plain hex
+pub(crate) fn parse_address(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// Numbers in .dbg files are decimal or 0x prefixed hex
+pub(crate) fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Splits a .dbg line such as `sym id=3,name="main",val=0x8000` into its kind and
+// fields, with the quotes taken off string values
+pub(crate) fn parse_dbg_line(line: &str) -> Option<(&str, HashMap<&str, &str>)> {
+    let (kind, rest) = line.trim().split_once(char::is_whitespace)?;
+    let mut fields = HashMap::new();
+    let mut rest = rest.trim();
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once('=')?;
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], quoted[end + 1..].trim_start_matches(','))
+            }
+            None => match after_key.split_once(',') {
+                Some((value, after)) => (value, after),
+                None => (after_key, ""),
+            },
+        };
+        fields.insert(key.trim(), value);
+        rest = after_value.trim();
+    }
+    Some((kind, fields))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formats_share_bank_aware_labels() {
+        let mut symbols = SymbolTable::new(0x4000);
+        symbols.parse_simple("; comment\n0010=counter\n$C000 = Reset\n").unwrap();
+        symbols.parse_nl("$C003#NMI_Handler#vblank\n$0300/10#buffer#\n#only a comment\n", Some(0)).unwrap();
+        symbols.parse_mlb("P:0006:IRQ_Handler:comment: with colons\nW:0010:save_slot\nR:0020:\n").unwrap();
+        symbols.parse_dbg(concat!(
+            "seg id=0,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16\n",
+            "sym id=0,name=\"main\",addrsize=absolute,scope=0,def=1,val=0xC010,seg=0,type=lab\n",
+            "sym id=1,name=\"@loop\",addrsize=absolute,scope=0,def=2,val=0xC010,seg=0,type=lab\n",
+            "sym id=2,name=\"WIDTH\",addrsize=zeropage,scope=0,def=3,val=0x20,type=equ\n",
+        ))
+        .unwrap();
+
+        assert_eq!(symbols.label(0x0010), Some("counter"));
+        // The 16KB bank is mirrored, labels show up at both addresses
+        assert_eq!(symbols.label(0x8000), Some("Reset"));
+        assert_eq!(symbols.label(0xC003), Some("NMI_Handler"));
+        assert_eq!(symbols.label(0x0300), Some("buffer"));
+        assert_eq!(symbols.label(0xC006), Some("IRQ_Handler"));
+        assert_eq!(symbols.label(0x6010), Some("save_slot"));
+        assert_eq!(symbols.label(0xC010), Some("main"));
+        assert_eq!(symbols.label(0x0020), None);
+
+        assert_eq!(symbols.resolve("NMI_Handler"), Some(Location::Prg(3)));
+        assert_eq!(symbols.address(Location::Prg(3)), 0xC003);
+        assert_eq!(symbols.resolve("@loop"), Some(Location::Prg(0x10)));
+        assert_eq!(symbols.resolve("WIDTH"), None);
+        assert_eq!(nl_bank("game.nes.2.nl"), Some(2));
+        assert_eq!(nl_bank("game.nes.ram.nl"), None);
+
+        assert!(symbols.parse_simple("C000 Reset").is_err());
+    }
+}
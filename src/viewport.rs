@@ -0,0 +1,83 @@
+// Where the picture goes in a window of any size
+pub const NES_WIDTH: u32 = 256;
+pub const NES_HEIGHT: u32 = 240;
+// Lines televisions hid at the top and bottom of the picture
+pub const OVERSCAN_LINES: u32 = 8;
+// NTSC pixels are slightly wider than tall
+const PIXEL_ASPECT: f64 = 8.0 / 7.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    // Whole multiples of the picture, square pixels, sharp at any size
+    Integer,
+    // As large as fits with the 8:7 pixel aspect of a television
+    Aspect,
+}
+
+impl Fit {
+    pub fn from_name(name: &str) -> Option<Fit> {
+        match name {
+            "integer" => Some(Fit::Integer),
+            "aspect" => Some(Fit::Aspect),
+            _ => None,
+        }
+    }
+
+    // Size of the picture at a scale, the window size it starts with
+    pub fn scaled(self, (width, height): (u32, u32), scale: u32) -> (u32, u32) {
+        match self {
+            Fit::Integer => (width * scale, height * scale),
+            Fit::Aspect => ((width as f64 * PIXEL_ASPECT * scale as f64).round() as u32, height * scale),
+        }
+    }
+
+    // Centered rectangle x, y, width, height for the picture, letterboxed
+    pub fn place(self, (window_width, window_height): (u32, u32), (width, height): (u32, u32)) -> (i32, i32, u32, u32) {
+        let (out_width, out_height) = match self {
+            Fit::Integer => {
+                let scale = (window_width / width).min(window_height / height).max(1);
+                (width * scale, height * scale)
+            }
+            Fit::Aspect => {
+                let aspect_width = width as f64 * PIXEL_ASPECT;
+                let scale = (window_width as f64 / aspect_width).min(window_height as f64 / height as f64);
+                ((aspect_width * scale).round() as u32, (height as f64 * scale).round() as u32)
+            }
+        };
+        let x = (window_width as i32 - out_width as i32) / 2;
+        let y = (window_height as i32 - out_height as i32) / 2;
+        (x, y, out_width, out_height)
+    }
+}
+
+// Rows of a picture left after cropping the overscan, first row and count.
+// Only full NES pictures are cropped, smaller screens have nothing hidden.
+pub fn visible_lines(height: u32, crop_overscan: bool) -> (u32, u32) {
+    if crop_overscan && height == NES_HEIGHT {
+        (OVERSCAN_LINES, height - 2 * OVERSCAN_LINES)
+    } else {
+        (0, height)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fit_picture() {
+        assert_eq!(Fit::Integer.scaled((NES_WIDTH, NES_HEIGHT), 3), (768, 720));
+        assert_eq!(Fit::Aspect.scaled((NES_WIDTH, NES_HEIGHT), 2), (585, 480));
+
+        // 1000x500 holds two whole copies of 256x224, letterboxed on both sides
+        assert_eq!(Fit::Integer.place((1000, 500), (256, 224)), (244, 26, 512, 448));
+        assert_eq!(Fit::Integer.place((100, 100), (256, 240)), (-78, -70, 256, 240));
+        assert_eq!(Fit::Aspect.place((1920, 1080), (256, 240)), (301, 0, 1317, 1080));
+
+        assert_eq!(visible_lines(240, true), (8, 224));
+        assert_eq!(visible_lines(240, false), (0, 240));
+        assert_eq!(visible_lines(16, true), (0, 16));
+        // The 32 line snake screen keeps its whole playfield
+        assert_eq!(visible_lines(32, true), (0, 32));
+    }
+}
@@ -0,0 +1,112 @@
+// $4017 bits: light sensed pulls bit 3 low, a pulled trigger sets bit 4
+const LIGHT_NOT_SENSED: u8 = 0b0000_1000;
+const TRIGGER_PULLED: u8 = 0b0001_0000;
+// The photodiode sees a few pixels around where the gun points
+const SENSE_RADIUS: isize = 2;
+// Average luma, out of 255, the diode reacts to
+const BRIGHT_LUMA: u32 = 160;
+
+// What is plugged into controller port 2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PortDevice {
+    #[default]
+    Joypad,
+    Zapper,
+}
+
+impl PortDevice {
+    pub fn from_name(name: &str) -> Option<PortDevice> {
+        match name {
+            "joypad" => Some(PortDevice::Joypad),
+            "zapper" => Some(PortDevice::Zapper),
+            _ => None,
+        }
+    }
+}
+
+// Light gun. Without a PPU to time the beam against, light is sensed from the
+// whole frame last shown rather than as the current scanline passes the aim.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Zapper {
+    pub trigger: bool,
+    // Pixel of the picture the gun points at, None when off screen
+    aim: Option<(usize, usize)>,
+    light: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper::default()
+    }
+
+    pub fn aim(&mut self, target: Option<(usize, usize)>) {
+        self.aim = target;
+    }
+
+    pub fn light(&self) -> bool {
+        self.light
+    }
+
+    // Looks at an RGB24 frame around the aim, call with every frame shown
+    pub fn sense(&mut self, rgb: &[u8], width: usize, height: usize) {
+        let Some((x, y)) = self.aim.filter(|&(x, y)| x < width && y < height) else {
+            self.light = false;
+            return;
+        };
+        let mut total = 0;
+        let mut count = 0;
+        for dy in -SENSE_RADIUS..=SENSE_RADIUS {
+            for dx in -SENSE_RADIUS..=SENSE_RADIUS {
+                let (px, py) = (x as isize + dx, y as isize + dy);
+                if px < 0 || py < 0 || px >= width as isize || py >= height as isize {
+                    continue;
+                }
+                let offset = (py as usize * width + px as usize) * 3;
+                let (r, g, b) = (rgb[offset] as u32, rgb[offset + 1] as u32, rgb[offset + 2] as u32);
+                total += (r * 299 + g * 587 + b * 114) / 1000;
+                count += 1;
+            }
+        }
+        self.light = total / count >= BRIGHT_LUMA;
+    }
+
+    pub fn read(&self) -> u8 {
+        let light = if self.light { 0 } else { LIGHT_NOT_SENSED };
+        let trigger = if self.trigger { TRIGGER_PULLED } else { 0 };
+        light | trigger
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{CPU, MEM};
+
+    #[test]
+    fn test_zapper_light_and_trigger() {
+        // 8x8 black frame with a white 4x4 square in the top left corner
+        let mut rgb = vec![0; 8 * 8 * 3];
+        for y in 0..4 {
+            for x in 0..4 {
+                rgb[(y * 8 + x) * 3..(y * 8 + x) * 3 + 3].copy_from_slice(&[255, 255, 255]);
+            }
+        }
+
+        let mut cpu = CPU::new();
+        cpu.port2 = PortDevice::Zapper;
+        assert_eq!(cpu.mem_read(0x4017), LIGHT_NOT_SENSED);
+
+        cpu.zapper.aim(Some((1, 1)));
+        cpu.zapper.sense(&rgb, 8, 8);
+        cpu.zapper.trigger = true;
+        assert!(cpu.zapper.light());
+        assert_eq!(cpu.mem_read(0x4017), TRIGGER_PULLED);
+
+        cpu.zapper.aim(Some((6, 6)));
+        cpu.zapper.sense(&rgb, 8, 8);
+        assert!(!cpu.zapper.light());
+        cpu.zapper.aim(Some((20, 1)));
+        cpu.zapper.sense(&rgb, 8, 8);
+        assert!(!cpu.zapper.light());
+    }
+}